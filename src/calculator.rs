@@ -1,54 +1,465 @@
-use std::fmt;
+use std::{ borrow::Borrow, collections::{ HashMap, VecDeque }, fmt, marker::PhantomData, time::{ Duration, Instant } };
 
+use crate::expr::Expr;
+
+#[derive(Debug)]
 enum RPNToken {
     Operation(char),
-    Number(f32),
+    Function(String, usize),
+    Number(f64),
+    Variable(String),
+}
+
+impl fmt::Display for RPNToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RPNToken::Operation('−') => write!(f, "neg"),
+            RPNToken::Operation('¬') => write!(f, "not"),
+            RPNToken::Operation(':') => write!(f, "?:"),
+            RPNToken::Operation(op) => write!(f, "{}", Calculator::operator_symbol(*op)),
+            // Variadic calls carry their argument count, as in `1 2 3 sum/3`.
+            RPNToken::Function(name, argc) if Calculator::function_arity(name).is_none() => {
+                write!(f, "{}/{}", name, argc)
+            }
+            RPNToken::Function(name, _) | RPNToken::Variable(name) => write!(f, "{}", name),
+            RPNToken::Number(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+/// Operators carry their postfix spelling, which is how they are written except for the
+/// conditional, `?:`, negation, `neg`, and logical not, `not`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Number(f64),
+    Operator(String),
+    Function(String, usize),
+    Variable(String),
+}
+
+impl From<&RPNToken> for Token {
+    fn from(token: &RPNToken) -> Self {
+        match token {
+            RPNToken::Operation(_) => Token::Operator(token.to_string()),
+            RPNToken::Function(name, argc) => Token::Function(name.clone(), *argc),
+            RPNToken::Number(n) => Token::Number(*n),
+            RPNToken::Variable(name) => Token::Variable(name.clone()),
+        }
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Operator(symbol) | Token::Function(symbol, _) | Token::Variable(symbol) => write!(f, "{}", symbol),
+            Token::Number(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct NumberFormat {
+    comma_grouping: bool,
+    decimal_sep: char,
+    sexagesimal: bool,
+    integer_only: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Previous {
+    Operator,
+    Number,
+    Close,
+    // A percent sign, which no operand may follow.
+    Percent,
+}
+
+const UNICODE_DIGIT_ZEROS: [u32; 20] = [
+    0x0660, 0x06F0, 0x07C0, 0x0966, 0x09E6, 0x0A66, 0x0AE6, 0x0B66, 0x0BE6, 0x0C66,
+    0x0CE6, 0x0D66, 0x0DE6, 0x0E50, 0x0ED0, 0x0F20, 0x1040, 0x1090, 0x17E0, 0x1810,
+];
+
+type BinaryFn<T> = fn(T, T) -> T;
+
+type BuiltInFn = fn(f64, f64) -> Result<f64, CalculatorError>;
+
+// How a binary operator computes its result. Built-in operators work in `f64` and can fail,
+// while those added with `register_operator` work in the calculator's number type.
+#[derive(Clone, Copy)]
+enum BinaryOp<T> {
+    BuiltIn(BuiltInFn),
+    Registered(BinaryFn<T>),
+}
+
+const MAX_EXACT_INTEGER: f64 = 9_007_199_254_740_992.0;
+
+const FUNCTIONS: [&str; 24] = [
+    "sqrt", "sin", "cos", "tan", "abs", "ln", "exp", "min", "max", "log", "floor", "ceil", "round", "trunc",
+    "gcd", "lcm", "pow", "sum", "avg", "sign", "pi", "e", "tau", "phi",
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Assoc {
+    Left,
+    Right,
+}
+
+// Operators as they are written, the single char each is carried as through the RPN stream,
+// and their precedence, associativity and name for error messages. The lexer takes the longest
+// written match. A symbol listed twice is read as its first entry, and the tokenizer picks the
+// second from context: `^` is xor with `with_caret_as_xor`, a prefix `-` is negation and a
+// prefix `!` is logical not.
+// How each operator is evaluated is kept apart, in `BINARY_OPERATORS` and `apply_unary`.
+const OPERATOR_TABLE: [(&str, char, u8, Assoc, &str); 26] = [
+    ("+", '+', 9, Assoc::Left, "addition"),
+    ("-", '-', 9, Assoc::Left, "subtraction"),
+    ("*", '*', 10, Assoc::Left, "multiplication"),
+    ("/", '/', 10, Assoc::Left, "division"),
+    ("//", '⫽', 10, Assoc::Left, "floor division"),
+    ("%", '%', 10, Assoc::Left, "modulo"),
+    ("^", '^', 12, Assoc::Right, "exponentiation"),
+    ("!", '!', 0, Assoc::Left, "factorial"),
+    ("~", '~', 11, Assoc::Left, "bitwise not"),
+    ("&", '&', 7, Assoc::Left, "bitwise and"),
+    ("|", '|', 5, Assoc::Left, "bitwise or"),
+    ("<<", '≪', 8, Assoc::Left, "left shift"),
+    (">>", '≫', 8, Assoc::Left, "right shift"),
+    ("<", '<', 4, Assoc::Left, "comparison"),
+    (">", '>', 4, Assoc::Left, "comparison"),
+    ("<=", '≤', 4, Assoc::Left, "comparison"),
+    (">=", '≥', 4, Assoc::Left, "comparison"),
+    ("==", '=', 3, Assoc::Left, "equality"),
+    ("!=", '≠', 3, Assoc::Left, "inequality"),
+    ("&&", '∧', 2, Assoc::Left, "logical and"),
+    ("||", '∨', 1, Assoc::Left, "logical or"),
+    ("?", '?', 0, Assoc::Right, "the conditional"),
+    (":", ':', 0, Assoc::Left, "the conditional"),
+    ("^", '⊻', 6, Assoc::Left, "bitwise xor"),
+    ("-", '−', 11, Assoc::Left, "negation"),
+    ("!", '¬', 11, Assoc::Left, "logical not"),
+];
+
+// The built-in binary operators, keyed by the char they are carried as. Every calculator starts
+// with these registered, next to any added with `register_operator`.
+const BINARY_OPERATORS: [(char, BuiltInFn); 20] = [
+    ('+', |a, b| Ok(a + b)),
+    ('-', |a, b| Ok(a - b)),
+    ('*', |a, b| Ok(a * b)),
+    ('/', |a, b| Ok(a / Calculator::divisor(a, b)?)),
+    ('⫽', |a, b| Ok((a / Calculator::divisor(a, b)?).floor())),
+    ('%', |a, b| Ok(a % Calculator::divisor(a, b)?)),
+    ('^', |a, b| Ok(a.powf(b))),
+    ('&', |a, b| Ok((Calculator::to_integer(a)? & Calculator::to_integer(b)?) as f64)),
+    ('|', |a, b| Ok((Calculator::to_integer(a)? | Calculator::to_integer(b)?) as f64)),
+    ('⊻', |a, b| Ok((Calculator::to_integer(a)? ^ Calculator::to_integer(b)?) as f64)),
+    ('≪', |a, b| Calculator::shift(a, b, Calculator::shift_left)),
+    ('≫', |a, b| Calculator::shift(a, b, Calculator::shift_right)),
+    ('=', |a, b| Ok((a == b) as u8 as f64)),
+    ('≠', |a, b| Ok((a != b) as u8 as f64)),
+    ('<', |a, b| Ok((a < b) as u8 as f64)),
+    ('>', |a, b| Ok((a > b) as u8 as f64)),
+    ('≤', |a, b| Ok((a <= b) as u8 as f64)),
+    ('≥', |a, b| Ok((a >= b) as u8 as f64)),
+    ('∧', |a, b| Ok((a != 0.0 && b != 0.0) as u8 as f64)),
+    ('∨', |a, b| Ok((a != 0.0 || b != 0.0) as u8 as f64)),
+];
+
+/// The number type results are produced in. Values are computed in `f64` and rounded to the
+/// calculator's type after every literal and every step, so a `Calculator<f32>` gives the same
+/// results as native `f32` arithmetic for the basic operators.
+pub trait Number: Copy + PartialEq + fmt::Debug + fmt::Display {
+    fn from_f64(value: f64) -> Self;
+    fn to_f64(self) -> f64;
+}
+
+impl Number for f64 {
+    fn from_f64(value: f64) -> Self {
+        value
+    }
+
+    fn to_f64(self) -> f64 {
+        self
+    }
+}
+
+impl Number for f32 {
+    fn from_f64(value: f64) -> Self {
+        value as f32
+    }
+
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
 }
 
-pub struct Calculator {
+pub struct Calculator<T: Number = f64> {
     tokens: Vec<RPNToken>,
+    tokenized: bool,
+    postfix: bool,
     expr: String,
+    percent_mode: bool,
+    caret_is_xor: bool,
+    comma_grouping: bool,
+    decimal_sep: char,
+    angle_mode: AngleMode,
+    precision: Option<usize>,
+    zero_epsilon: f64,
+    integer_mode: bool,
+    sexagesimal: bool,
+    integer_only: bool,
+    div_by_zero: DivByZeroPolicy,
+    max_len: Option<usize>,
+    constants: HashMap<String, f64>,
+    variables: HashMap<String, f64>,
+    operators: HashMap<char, (u8, BinaryOp<T>)>,
+    ans: f64,
+    memory: f64,
+    history: Vec<f64>,
+    op_count: usize,
+    warnings: Vec<String>,
+    number: PhantomData<T>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum AngleMode {
+    #[default]
+    Radians,
+    Degrees,
+}
+
+/// What dividing by zero produces. With `Infinity` a non-zero dividend gives an infinity of its
+/// own sign, while `0 / 0` and any remainder by zero are NaN.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DivByZeroPolicy {
+    #[default]
+    Error,
+    Infinity,
+    Nan,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CalcValue {
+    Int(i64),
+    Float(f64),
+}
+
+impl From<f64> for CalcValue {
+    fn from(value: f64) -> Self {
+        if value.fract() == 0.0 && value >= i64::MIN as f64 && value < i64::MAX as f64 {
+            CalcValue::Int(value as i64)
+        } else {
+            CalcValue::Float(value)
+        }
+    }
+}
+
+impl fmt::Display for CalcValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CalcValue::Int(n) => write!(f, "{}", n),
+            CalcValue::Float(n) => write!(f, "{}", n),
+        }
+    }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum CalculatorError {
-    UnsupportedToken,
-    MismatchedParantheses,
+    UnsupportedToken { position: usize, token: String },
+    MismatchedParantheses { position: usize },
+    UnexpectedOperator { position: usize, operator: String },
+    UnboundVariable { name: String },
     InvalidExpression,
     InvalidDecimal,
-    ZeroDivision,
+    DecimalsDisabled,
+    ExpressionTooLong { max_len: usize },
+    ZeroDivision { dividend: f64 },
+    Overflow,
+    MathDomain,
+    EmptyExpression,
 }
 
 impl fmt::Display for CalculatorError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            CalculatorError::UnsupportedToken =>
-                write!(f, "Error: The expression contains an unsupported token."),
-            CalculatorError::MismatchedParantheses =>
-                write!(f, "Error: Ensure all parantheses is properly closed and opened."),
+            CalculatorError::UnsupportedToken { position, token } =>
+                write!(f, "Error at position {}: The expression contains an unsupported token '{}'.", position, token),
+            CalculatorError::MismatchedParantheses { position } =>
+                write!(f, "Error at position {}: Ensure all parantheses is properly closed and opened.", position),
+            CalculatorError::UnexpectedOperator { position, operator } =>
+                write!(f, "Error at position {}: Unexpected operator '{}', expected a number.", position, operator),
+            CalculatorError::UnboundVariable { name } =>
+                write!(f, "Error: The variable '{}' has no value.", name),
             CalculatorError::InvalidExpression =>
                 write!(f, "Error: The expression is invalid — it may be incomplete or malformed."),
             CalculatorError::InvalidDecimal =>
                 write!(f, "Error: The expression contains an invalid decimal number."),
-            CalculatorError::ZeroDivision =>
-                write!(f, "Error: Dividing by 0 is a mathematically undefeined behaviour."),
+            CalculatorError::DecimalsDisabled =>
+                write!(f, "Error: Decimals are disabled in integer mode."),
+            CalculatorError::ExpressionTooLong { max_len } =>
+                write!(f, "Error: The expression is longer than the limit of {} bytes.", max_len),
+            CalculatorError::ZeroDivision { dividend } =>
+                write!(f, "Error: cannot divide {} by 0.", dividend),
+            CalculatorError::Overflow =>
+                write!(f, "Error: The result is too large to be represented."),
+            CalculatorError::MathDomain =>
+                write!(f, "Error: The operation is undefined for the given input."),
+            CalculatorError::EmptyExpression =>
+                write!(f, "Error: The expression is empty."),
         }
     }
 }
 
+impl std::error::Error for CalculatorError {}
+
+impl CalculatorError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CalculatorError::UnsupportedToken { .. } => "unsupported_token",
+            CalculatorError::MismatchedParantheses { .. } => "mismatched_parens",
+            CalculatorError::UnexpectedOperator { .. } => "unexpected_operator",
+            CalculatorError::UnboundVariable { .. } => "unbound_variable",
+            CalculatorError::InvalidExpression => "invalid_expression",
+            CalculatorError::InvalidDecimal => "invalid_decimal",
+            CalculatorError::DecimalsDisabled => "decimals_disabled",
+            CalculatorError::ExpressionTooLong { .. } => "expression_too_long",
+            CalculatorError::ZeroDivision { .. } => "zero_division",
+            CalculatorError::Overflow => "overflow",
+            CalculatorError::MathDomain => "math_domain",
+            CalculatorError::EmptyExpression => "empty_expression",
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            CalculatorError::UnsupportedToken { .. }
+            | CalculatorError::MismatchedParantheses { .. }
+            | CalculatorError::UnexpectedOperator { .. }
+            | CalculatorError::UnboundVariable { .. }
+            | CalculatorError::InvalidExpression
+            | CalculatorError::InvalidDecimal
+            | CalculatorError::DecimalsDisabled
+            | CalculatorError::ExpressionTooLong { .. }
+            | CalculatorError::EmptyExpression => 2,
+            CalculatorError::ZeroDivision { .. } => 3,
+            CalculatorError::Overflow => 4,
+            CalculatorError::MathDomain => 5,
+        }
+    }
+}
+
+// Shows the token count only while tokens are stored, which is after `try_new`, `parse`,
+// `to_rpn` or a postfix evaluation. `eval` streams its tokens, so it leaves none behind.
+impl<T: Number> fmt::Debug for Calculator<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("Calculator");
+        debug.field("expr", &self.expr);
+        if !self.tokens.is_empty() {
+            debug.field("tokens", &self.tokens.len());
+        }
+        debug.finish()
+    }
+}
+
+pub struct CalculatorBuilder<T: Number = f64> {
+    calculator: Calculator<T>,
+}
+
+impl<T: Number> CalculatorBuilder<T> {
+    pub fn expr(mut self, expr: &str) -> Self {
+        self.calculator.expr = expr.to_string();
+        self
+    }
+
+    pub fn percent_mode(mut self, enabled: bool) -> Self {
+        self.calculator.percent_mode = enabled;
+        self
+    }
+
+    pub fn caret_is_xor(mut self, enabled: bool) -> Self {
+        self.calculator.caret_is_xor = enabled;
+        self
+    }
+
+    pub fn comma_grouping(mut self, enabled: bool) -> Self {
+        self.calculator.comma_grouping = enabled;
+        self
+    }
+
+    pub fn decimal_separator(mut self, decimal_sep: char) -> Self {
+        self.calculator.decimal_sep = decimal_sep;
+        self
+    }
+
+    pub fn angle_mode(mut self, angle_mode: AngleMode) -> Self {
+        self.calculator.angle_mode = angle_mode;
+        self
+    }
+
+    pub fn precision(mut self, digits: usize) -> Self {
+        self.calculator.precision = Some(digits);
+        self
+    }
+
+    pub fn zero_epsilon(mut self, epsilon: f64) -> Self {
+        self.calculator.zero_epsilon = epsilon;
+        self
+    }
+
+    pub fn integer_mode(mut self, enabled: bool) -> Self {
+        self.calculator.integer_mode = enabled;
+        self
+    }
+
+    pub fn sexagesimal(mut self, enabled: bool) -> Self {
+        self.calculator.sexagesimal = enabled;
+        self
+    }
+
+    pub fn div_by_zero(mut self, policy: DivByZeroPolicy) -> Self {
+        self.calculator.div_by_zero = policy;
+        self
+    }
+
+    pub fn integer_only(mut self, enabled: bool) -> Self {
+        self.calculator.integer_only = enabled;
+        self
+    }
+
+    pub fn max_len(mut self, max_len: usize) -> Self {
+        self.calculator.max_len = Some(max_len);
+        self
+    }
+
+    pub fn build(self) -> Calculator<T> {
+        self.calculator
+    }
+}
+
+// `f64` shorthands, so `Calculator::new` needs no type annotation. The `_typed` constructors
+// take the number type from context or a turbofish, as in `Calculator::<f32>::new_typed`.
 impl Calculator {
     pub fn new(expr: String) -> Self {
-        Calculator {
-            tokens: Vec::new(),
-            expr,
-        }
+        Calculator::new_typed(expr)
+    }
+
+    pub fn from_postfix(expr: String) -> Self {
+        Calculator::from_postfix_typed(expr)
+    }
+
+    pub fn try_new(expr: String) -> Result<Self, CalculatorError> {
+        Calculator::try_new_typed(expr)
+    }
+
+    pub fn builder() -> CalculatorBuilder {
+        Calculator::builder_typed()
     }
 
     fn parse_number(
-        &self,
         chars: &[char],
         i: &mut usize,
-        is_negative: bool
-    ) -> Result<f32, CalculatorError> {
+        is_negative: bool,
+        format: NumberFormat
+    ) -> Result<f64, CalculatorError> {
         let mut num_str = String::new();
         let mut dotted = false;
 
@@ -56,129 +467,2888 @@ impl Calculator {
             num_str.push('-');
         }
 
-        while *i < chars.len() && (chars[*i].is_ascii_digit() || chars[*i] == '.') {
-            if chars[*i] == '.' {
+        let radix = match (chars.get(*i), chars.get(*i + 1)) {
+            (Some('0'), Some('x' | 'X')) => Some(16),
+            (Some('0'), Some('o' | 'O')) => Some(8),
+            (Some('0'), Some('b' | 'B')) => Some(2),
+            _ => None,
+        };
+        if let Some(radix) = radix {
+            *i += 2;
+            while *i < chars.len() && chars[*i].is_ascii_alphanumeric() {
+                num_str.push(chars[*i]);
+                *i += 1;
+            }
+            return i64::from_str_radix(&num_str, radix)
+                .map(|n| n as f64)
+                .map_err(|_| CalculatorError::InvalidExpression);
+        }
+
+        let is_separator = |c: char| {
+            c == '_' || (format.comma_grouping && format.decimal_sep != ',' && c == ',')
+        };
+        let is_decimal = |c: char| c == format.decimal_sep;
+
+        while *i < chars.len() && (chars[*i].is_ascii_digit() || is_decimal(chars[*i]) || is_separator(chars[*i])) {
+            if is_separator(chars[*i]) {
+                let after_digit = *i > 0 && chars[*i - 1].is_ascii_digit();
+                let before_digit = chars.get(*i + 1).is_some_and(|c| c.is_ascii_digit());
+                if !after_digit || !before_digit {
+                    if chars[*i] == ',' {
+                        break;
+                    }
+                    return Err(CalculatorError::InvalidExpression);
+                }
+                let group = chars[*i + 1..].iter().take_while(|c| c.is_ascii_digit()).count();
+                if chars[*i] == ',' && group != 3 {
+                    return Err(CalculatorError::InvalidExpression);
+                }
+                *i += 1;
+                continue;
+            }
+            if is_decimal(chars[*i]) {
+                if chars[*i] == ',' && !chars.get(*i + 1).is_some_and(|c| c.is_ascii_digit()) {
+                    break;
+                }
+                if format.integer_only {
+                    return Err(CalculatorError::DecimalsDisabled);
+                }
                 if dotted {
                     return Err(CalculatorError::InvalidDecimal);
                 }
                 dotted = true;
             }
-            num_str.push(chars[*i]);
+            num_str.push(if is_decimal(chars[*i]) { '.' } else { chars[*i] });
             *i += 1;
         }
-        num_str.parse::<f32>().map_err(|_| CalculatorError::InvalidExpression)
-    }
 
-    fn tokenizer(&mut self) -> Result<(), CalculatorError> {
-        let precedence = |op: char| {
-            match op {
-                '+' | '-' => 1u8,
-                '*' | '/' => 2u8,
-                _ => 0u8,
+        if *i < chars.len() && matches!(chars[*i], 'e' | 'E') {
+            let mut j = *i + 1;
+            if j < chars.len() && matches!(chars[j], '+' | '-') {
+                j += 1;
             }
+            if j < chars.len() && chars[j].is_ascii_digit() {
+                while j < chars.len() && chars[j].is_ascii_digit() {
+                    j += 1;
+                }
+                num_str.extend(&chars[*i..j]);
+                *i = j;
+            }
+        }
+        let value = num_str.parse::<f64>().map_err(|_| CalculatorError::InvalidExpression)?;
+        // Only the `inf` literal may be infinite; `1e400` is too large rather than infinity.
+        if value.is_infinite() {
+            return Err(CalculatorError::Overflow);
+        }
+        let value = if format.sexagesimal && Calculator::continues_sexagesimal(chars, *i) {
+            Calculator::parse_sexagesimal(chars, i, value, format)?
+        } else {
+            value
         };
+        // An exponent can give a fraction without a decimal point, as in `1e-3`.
+        if format.integer_only && value.fract() != 0.0 {
+            return Err(CalculatorError::DecimalsDisabled);
+        }
+        Ok(value)
+    }
 
-        let mut i = 0;
-        self.tokens.clear();
-        let mut operations = Vec::new();
-        let chars: Vec<char> = self.expr.chars().collect();
+    fn continues_sexagesimal(chars: &[char], i: usize) -> bool {
+        chars.get(i) == Some(&':') && chars.get(i + 1).is_some_and(|c| c.is_ascii_digit())
+    }
 
-        while i < chars.len() {
-            let c = chars[i];
+    // `1:30` is 1.5 and `12:30:00` is 12.5. Minutes and seconds must be below 60, and only the
+    // last part may have a fraction.
+    fn parse_sexagesimal(
+        chars: &[char],
+        i: &mut usize,
+        whole: f64,
+        format: NumberFormat
+    ) -> Result<f64, CalculatorError> {
+        let format = NumberFormat { sexagesimal: false, ..format };
+        let mut value = whole.abs();
+        let mut last = value;
+        let mut scale = 1.0;
 
-            if c.is_whitespace() {
-                i += 1;
-                continue;
+        for _ in 0..2 {
+            if !Calculator::continues_sexagesimal(chars, *i) {
+                break;
             }
-
-            if c == '-' {
-                let is_unary = i == 0 || matches!(chars[i - 1], '(' | '+' | '-' | '*' | '/' | ' ');
-                if is_unary && i + 1 < chars.len() && chars[i + 1].is_ascii_digit() {
-                    i += 1;
-                    let number = self.parse_number(&chars, &mut i, true)?;
-                    self.tokens.push(RPNToken::Number(number));
-                    continue;
-                }
+            if last.fract() != 0.0 {
+                return Err(CalculatorError::InvalidExpression);
             }
-
-            if c.is_ascii_digit() || c == '.' {
-                let number = self.parse_number(&chars, &mut i, false)?;
-                self.tokens.push(RPNToken::Number(number));
-                continue;
+            *i += 1;
+            last = Calculator::parse_number(chars, i, false, format)?;
+            if last >= 60.0 {
+                return Err(CalculatorError::InvalidExpression);
             }
+            scale /= 60.0;
+            value += last * scale;
+        }
 
-            match c {
-                '+' | '-' | '*' | '/' => {
-                    while let Some(&top) = operations.last() {
-                        if precedence(top) >= precedence(c) {
-                            self.tokens.push(RPNToken::Operation(operations.pop().unwrap()));
-                        } else {
-                            break;
-                        }
-                    }
-                    operations.push(c);
+        if Calculator::continues_sexagesimal(chars, *i) {
+            return Err(CalculatorError::InvalidExpression);
+        }
+        Ok(if whole.is_sign_negative() { -value } else { value })
+    }
+
+    fn constant(name: &str) -> Option<f64> {
+        match name {
+            "pi" => Some(std::f64::consts::PI),
+            "e" => Some(std::f64::consts::E),
+            "tau" => Some(std::f64::consts::TAU),
+            "phi" => Some(1.618_033_988_749_895),
+            "inf" => Some(f64::INFINITY),
+            "nan" => Some(f64::NAN),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn apply_function(
+        name: &str,
+        args: &[f64],
+        angle_mode: AngleMode
+    ) -> Result<f64, CalculatorError> {
+        let to_radians = |x: f64| match angle_mode {
+            AngleMode::Radians => x,
+            AngleMode::Degrees => x.to_radians(),
+        };
+
+        let out = match (name, args) {
+            // The built-in constants can also be called with no arguments, as in `pi()`.
+            (name, []) if let Some(value) = Calculator::constant(name) => Ok(value),
+            ("sqrt", [x]) if *x < 0.0 => Err(CalculatorError::MathDomain),
+            ("ln", [x]) if *x <= 0.0 => Err(CalculatorError::MathDomain),
+            ("sqrt", [x]) => Ok(x.sqrt()),
+            ("sin", [x]) => Ok(to_radians(*x).sin()),
+            ("cos", [x]) => Ok(to_radians(*x).cos()),
+            ("tan", [x]) => Ok(to_radians(*x).tan()),
+            ("abs", [x]) => Ok(x.abs()),
+            ("ln", [x]) => Ok(x.ln()),
+            ("exp", [x]) => Ok(x.exp()),
+            ("floor", [x]) => Ok(x.floor()),
+            ("ceil", [x]) => Ok(x.ceil()),
+            // Halves round away from zero, so `round(2.5)` is 3 and `round(-2.5)` is -3.
+            ("round", [x]) => Ok(x.round()),
+            ("trunc", [x]) => Ok(x.trunc()),
+            // `signum` gives 1 for `0` and -1 for `-0`, but the sign of zero is zero.
+            ("sign", [x]) if *x == 0.0 => Ok(0.0),
+            ("sign", [x]) => Ok(x.signum()),
+            ("min", [a, b]) => Ok(a.min(*b)),
+            ("max", [a, b]) => Ok(a.max(*b)),
+            ("pow", [base, exp]) => Ok(base.powf(*exp)),
+            ("sum", [_, ..]) => Ok(args.iter().sum()),
+            ("avg", [_, ..]) => Ok(args.iter().sum::<f64>() / args.len() as f64),
+            ("log", [base, value]) => {
+                if *base <= 0.0 || *value <= 0.0 {
+                    return Err(CalculatorError::MathDomain);
                 }
-                '(' => operations.push(c),
-                ')' => {
-                    while let Some(top) = operations.pop() {
-                        if top == '(' {
-                            break;
-                        }
-                        self.tokens.push(RPNToken::Operation(top));
-                    }
+                if *base == 1.0 {
+                    return Err(CalculatorError::InvalidExpression);
                 }
-                _ => {
-                    return Err(CalculatorError::UnsupportedToken);
+                match base {
+                    2.0 => Ok(value.log2()),
+                    10.0 => Ok(value.log10()),
+                    _ => Ok(value.log(*base)),
+                }
+            }
+            ("gcd", [a, b]) => Ok(Self::gcd(Self::to_integer(*a)?, Self::to_integer(*b)?) as f64),
+            ("lcm", [a, b]) => {
+                let (a, b) = (Self::to_integer(*a)?, Self::to_integer(*b)?);
+                if a == 0 || b == 0 {
+                    return Ok(0.0);
                 }
+                (a.unsigned_abs() / Self::gcd(a, b))
+                    .checked_mul(b.unsigned_abs())
+                    .map(|n| n as f64)
+                    .ok_or(CalculatorError::Overflow)
             }
+            _ => Err(CalculatorError::InvalidExpression),
+        }?;
+        Self::check_overflow(out, args)
+    }
 
-            i += 1;
+    // `-0.0 == 0.0`, so both signed zeros are caught.
+    fn divisor(a: f64, b: f64) -> Result<f64, CalculatorError> {
+        if b == 0.0 {
+            return Err(CalculatorError::ZeroDivision { dividend: a });
+        }
+        Ok(b)
+    }
+
+    fn shift(a: f64, b: f64, shift: fn(i64, u32) -> Result<i64, CalculatorError>) -> Result<f64, CalculatorError> {
+        let value = Self::to_integer(a)?;
+        let by = u32::try_from(Self::to_integer(b)?).map_err(|_| CalculatorError::InvalidExpression)?;
+        shift(value, by).map(|n| n as f64)
+    }
+
+    // Shifting a bit into or past the sign bit overflows, rather than wrapping to a negative.
+    fn shift_left(value: i64, by: u32) -> Result<i64, CalculatorError> {
+        match value.checked_shl(by) {
+            Some(shifted) if shifted >> by == value => Ok(shifted),
+            _ if value == 0 => Ok(0),
+            _ => Err(CalculatorError::Overflow),
         }
+    }
+
+    fn shift_right(value: i64, by: u32) -> Result<i64, CalculatorError> {
+        value.checked_shr(by).ok_or(CalculatorError::InvalidExpression)
+    }
 
-        while let Some(op) = operations.pop() {
-            if op == '(' || op == ')' {
-                return Err(CalculatorError::MismatchedParantheses);
+    fn apply_integer(op: char, a: f64, b: f64) -> Result<f64, CalculatorError> {
+        let (a, b) = (Calculator::to_integer(a)?, Calculator::to_integer(b)?);
+        let out = match op {
+            '+' => a.checked_add(b),
+            '-' => a.checked_sub(b),
+            '*' => a.checked_mul(b),
+            _ if a.checked_rem(b).ok_or(CalculatorError::Overflow)? != 0 => {
+                return Err(CalculatorError::InvalidExpression);
             }
-            self.tokens.push(RPNToken::Operation(op));
+            _ => a.checked_div(b),
+        };
+        out.map(|n| n as f64).ok_or(CalculatorError::Overflow)
+    }
+
+    pub(crate) fn apply_unary(op: char, x: f64) -> Result<f64, CalculatorError> {
+        let out = match op {
+            '!' => Self::factorial(x),
+            '−' => Ok(-x),
+            '~' => Ok(!Self::to_integer(x)? as f64),
+            '¬' => Ok((x == 0.0) as u8 as f64),
+            _ => Err(CalculatorError::InvalidExpression),
+        }?;
+        Self::check_overflow(out, &[x])
+    }
+
+    fn check_overflow(out: f64, inputs: &[f64]) -> Result<f64, CalculatorError> {
+        if out.is_finite() || !inputs.iter().all(|x| x.is_finite()) {
+            return Ok(out);
         }
+        if out.is_nan() {
+            return Err(CalculatorError::MathDomain);
+        }
+        Err(CalculatorError::Overflow)
+    }
 
-        Ok(())
+    fn gcd(a: i64, b: i64) -> u64 {
+        let (mut a, mut b) = (a.unsigned_abs(), b.unsigned_abs());
+        while b != 0 {
+            (a, b) = (b, a % b);
+        }
+        a
     }
 
-    pub fn eval(&mut self) -> Result<f32, CalculatorError> {
-        let mut result: Vec<f32> = Vec::new();
-        self.tokenizer()?;
+    fn factorial(x: f64) -> Result<f64, CalculatorError> {
+        if x < 0.0 || x.fract() != 0.0 {
+            return Err(CalculatorError::InvalidExpression);
+        }
+        if x > 170.0 {
+            return Ok(f64::INFINITY);
+        }
+        Ok((2..=x as u64).fold(1.0, |acc, k| acc * k as f64))
+    }
 
-        for token in &self.tokens {
-            match token {
-                RPNToken::Operation(op) => {
-                    if result.len() < 2 {
-                        return Err(CalculatorError::InvalidExpression);
-                    }
-                    let b = result.pop().ok_or(CalculatorError::InvalidExpression)?;
-                    let a = result.pop().ok_or(CalculatorError::InvalidExpression)?;
-                    let out = match op {
-                        '+' => a + b,
-                        '-' => a - b,
-                        '*' => a * b,
-                        '/' => {
-                            if b == 0.0 {
-                                return Err(CalculatorError::ZeroDivision);
-                            }
-                            a / b
-                        }
-                        _ => unreachable!(),
-                    };
-                    result.push(out);
-                }
-                RPNToken::Number(n) => result.push(*n),
+    /// How an operator is written in infix, where logical not is a prefix `!`.
+    pub(crate) fn operator_symbol(op: char) -> String {
+        Calculator::operator_entry(op).map_or(op.to_string(), |&(written, ..)| written.to_string())
+    }
+
+    pub(crate) fn precedence(op: char) -> u8 {
+        Calculator::operator_entry(op).map_or(0, |&(_, _, precedence, _, _)| precedence)
+    }
+
+    pub(crate) fn is_right_associative(op: char) -> bool {
+        Calculator::operator_entry(op).is_some_and(|&(_, _, _, assoc, _)| assoc == Assoc::Right)
+    }
+
+    fn operator_entry(op: char) -> Option<&'static (&'static str, char, u8, Assoc, &'static str)> {
+        OPERATOR_TABLE.iter().find(|&&(_, entry, ..)| entry == op)
+    }
+
+    fn operator_name(op: char) -> String {
+        match Calculator::operator_entry(op) {
+            Some(&(_, _, _, _, name)) => name.to_string(),
+            None => format!("the '{}' operator", op),
+        }
+    }
+
+    pub(crate) fn is_unary(op: char) -> bool {
+        matches!(op, '!' | '−' | '~' | '¬')
+    }
+
+    fn to_integer(x: f64) -> Result<i64, CalculatorError> {
+        if x.fract() != 0.0 || x < i64::MIN as f64 || x >= i64::MAX as f64 {
+            return Err(CalculatorError::InvalidExpression);
+        }
+        Ok(x as i64)
+    }
+
+    // Zero-width characters and the byte order mark are invisible and often pasted in along
+    // with an expression, so they are skipped like whitespace.
+    fn is_ignorable(c: char) -> bool {
+        matches!(c, '\u{200B}' | '\u{200C}' | '\u{200D}' | '\u{2060}' | '\u{FEFF}')
+    }
+
+    // Control characters are named by codepoint so an error does not print them raw.
+    fn describe_char(c: char) -> String {
+        if c.is_control() { format!("U+{:04X}", c as u32) } else { c.to_string() }
+    }
+
+    fn normalize_char(c: char) -> char {
+        let code = c as u32;
+        if (0xFF01..=0xFF5E).contains(&code) {
+            return char::from_u32(code - 0xFEE0).unwrap_or(c);
+        }
+        for zero in UNICODE_DIGIT_ZEROS {
+            if (zero..zero + 10).contains(&code) {
+                return char::from_digit(code - zero, 10).unwrap_or(c);
             }
         }
+        c
+    }
 
-        if let Some(value) = result.pop() {
-            Ok(value)
+    // Skips a single leading `=` so spreadsheet-style formulas like `=2+2` are accepted.
+    fn formula_start(chars: &[char]) -> usize {
+        let start = chars.iter().position(|c| !c.is_whitespace()).unwrap_or(0);
+        if chars.get(start) == Some(&'=') && chars.get(start + 1) != Some(&'=') {
+            start + 1
         } else {
-            Err(CalculatorError::InvalidExpression)
+            0
         }
     }
+
+    fn operator_from_symbol(symbol: &str) -> Option<char> {
+        OPERATOR_TABLE.iter().find(|&&(written, ..)| written == symbol).map(|&(_, op, ..)| op)
+    }
+
+    // In postfix input the conditional is written as one `?:` word taking three operands, and
+    // negation and logical not as `neg` and `not`, since `-` is subtraction and `!` factorial.
+    fn postfix_operator(symbol: &str) -> Option<char> {
+        match symbol {
+            "?:" => Some(':'),
+            "neg" => Some('−'),
+            "not" => Some('¬'),
+            "?" | ":" => None,
+            _ => Calculator::operator_from_symbol(symbol),
+        }
+    }
+
+    // Variadic functions have no fixed arity. In postfix input they take every value on the stack,
+    // unless written with a count, as in `sum/3`.
+    fn function_arity(name: &str) -> Option<usize> {
+        match name {
+            "sum" | "avg" => None,
+            "pi" | "e" | "tau" | "phi" => Some(0),
+            "min" | "max" | "log" | "gcd" | "lcm" | "pow" => Some(2),
+            _ => Some(1),
+        }
+    }
+
+    fn variadic_call(word: &str) -> Option<(&str, usize)> {
+        let (name, argc) = word.split_once('/')?;
+        let argc = argc.parse().ok()?;
+        (FUNCTIONS.contains(&name) && Calculator::function_arity(name).is_none()).then_some((name, argc))
+    }
+
+    fn arity(token: &RPNToken) -> usize {
+        match token {
+            RPNToken::Number(_) | RPNToken::Variable(_) => 0,
+            RPNToken::Operation(op) if Self::is_unary(*op) => 1,
+            RPNToken::Operation(':') => 3,
+            RPNToken::Operation(_) => 2,
+            RPNToken::Function(_, argc) => *argc,
+        }
+    }
+}
+
+impl<T: Number> Calculator<T> {
+    pub fn new_typed(expr: String) -> Self {
+        Calculator {
+            tokens: Vec::new(),
+            tokenized: false,
+            postfix: false,
+            expr,
+            percent_mode: false,
+            caret_is_xor: false,
+            comma_grouping: false,
+            decimal_sep: '.',
+            angle_mode: AngleMode::Radians,
+            precision: None,
+            zero_epsilon: 0.0,
+            integer_mode: false,
+            sexagesimal: false,
+            integer_only: false,
+            div_by_zero: DivByZeroPolicy::Error,
+            max_len: None,
+            constants: HashMap::new(),
+            variables: HashMap::new(),
+            operators: BINARY_OPERATORS
+                .iter()
+                .map(|&(op, f)| (op, (Calculator::precedence(op), BinaryOp::BuiltIn(f))))
+                .collect(),
+            ans: 0.0,
+            memory: 0.0,
+            history: Vec::new(),
+            op_count: 0,
+            warnings: Vec::new(),
+            number: PhantomData,
+        }
+    }
+
+    pub fn from_postfix_typed(expr: String) -> Self {
+        Calculator { postfix: true, ..Calculator::new_typed(expr) }
+    }
+
+    pub fn try_new_typed(expr: String) -> Result<Self, CalculatorError> {
+        let mut calculator = Calculator::new_typed(expr);
+        calculator.tokenizer(false)?;
+        calculator.validate_tokens()?;
+        calculator.tokenized = true;
+        Ok(calculator)
+    }
+
+    pub fn builder_typed() -> CalculatorBuilder<T> {
+        CalculatorBuilder { calculator: Calculator::new_typed(String::new()) }
+    }
+
+    /// Lets `,` group digits like `_` does, so `1,000,000` is one number. A comma between two
+    /// digits is then always read as grouping and must be followed by exactly three digits, so
+    /// `1,00` is an error and `max(1,2)` must be written `max(1, 2)`.
+    pub fn with_comma_grouping(mut self) -> Self {
+        self.comma_grouping = true;
+        self
+    }
+
+    /// Reads `^` as bitwise XOR of two integers, binding between `&` and `|` as in C, instead of
+    /// as exponentiation. `pow` is still available for powers.
+    pub fn with_caret_xor(mut self) -> Self {
+        self.caret_is_xor = true;
+        self
+    }
+
+    /// With `,` as the decimal separator `3,14` is one number and `.` no longer starts a decimal.
+    /// A comma followed by a digit is always a decimal point, so function arguments must be
+    /// separated by `, ` (comma and space), and comma grouping is ignored.
+    pub fn with_decimal_separator(mut self, decimal_sep: char) -> Self {
+        self.decimal_sep = decimal_sep;
+        self
+    }
+
+    pub fn with_angle_mode(mut self, angle_mode: AngleMode) -> Self {
+        self.angle_mode = angle_mode;
+        self
+    }
+
+    pub fn with_precision(mut self, digits: usize) -> Self {
+        self.precision = Some(digits);
+        self
+    }
+
+    /// Divisors whose magnitude is at most `epsilon` are reported as division by zero. Without it
+    /// only an exact zero (`0` or `-0`) is, and a denormal divisor such as `1e-310` reports an
+    /// overflow once the quotient stops being finite.
+    pub fn with_zero_epsilon(mut self, epsilon: f64) -> Self {
+        self.zero_epsilon = epsilon;
+        self
+    }
+
+    /// Evaluates with exact integers only. Literals and constants that are not whole numbers,
+    /// and divisions that leave a remainder, are invalid expressions, so `7 / 2` fails while
+    /// `8 / 2` is `4`. Results must stay below 2^53 in magnitude, the range an `f64` holds
+    /// exactly, or they are reported as an overflow.
+    pub fn with_integer_mode(mut self) -> Self {
+        self.integer_mode = true;
+        self
+    }
+
+    /// Reads colon-separated literals such as `1:30` or `12:30:00` as hours (or degrees),
+    /// minutes and seconds. The conditional operator then needs a space before its `:` when a
+    /// number follows, as in `x ? 1 : 2`.
+    pub fn with_sexagesimal(mut self) -> Self {
+        self.sexagesimal = true;
+        self
+    }
+
+    /// Rejects any literal written with a decimal point, as in `2.5` or even `2.0`, and any with a
+    /// fraction, as in `1e-3`, while `1e3` is allowed. Results may still have fractions, so
+    /// `7 / 2` is `3.5`; combine with `with_integer_mode` to forbid those.
+    pub fn with_integer_only(mut self) -> Self {
+        self.integer_only = true;
+        self
+    }
+
+    pub fn with_div_by_zero(mut self, policy: DivByZeroPolicy) -> Self {
+        self.div_by_zero = policy;
+        self
+    }
+
+    /// Rejects expressions longer than `max_len` bytes before reading any of them, for callers
+    /// that evaluate untrusted input. There is no limit by default.
+    pub fn with_max_len(mut self, max_len: usize) -> Self {
+        self.max_len = Some(max_len);
+        self
+    }
+
+    pub fn set_expr(&mut self, expr: String) {
+        self.expr = expr;
+        self.tokens.clear();
+        self.tokenized = false;
+        self.warnings.clear();
+    }
+
+    pub fn variable(&self, name: &str) -> Option<T> {
+        self.variables.get(name).map(|value| T::from_f64(*value))
+    }
+
+    pub fn ans(&self) -> T {
+        T::from_f64(self.ans)
+    }
+
+    pub fn memory(&self) -> T {
+        T::from_f64(self.memory)
+    }
+
+    /// The number of operators and functions applied by the last `eval`, whether or not it
+    /// succeeded. Subexpressions folded by `parse` are not counted again.
+    pub fn operations_performed(&self) -> usize {
+        self.op_count
+    }
+
+    /// Literals that were rounded when the expression was last read, such as `16777217` in a
+    /// `Calculator<f32>`. They do not stop evaluation.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    // Compares a literal's digits with the value it was read as. Literals in exponent, radix or
+    // sexagesimal notation are not checked.
+    fn precision_warning(&self, literal: &[char], value: f64) -> Option<String> {
+        if literal.iter().any(|&c| c.is_ascii_alphabetic() || c == ':') {
+            return None;
+        }
+        let digits: String = literal
+            .iter()
+            .filter(|&&c| c.is_ascii_digit() || c == self.decimal_sep)
+            .map(|&c| if c == self.decimal_sep { '.' } else { c })
+            .collect();
+        let mut digits = digits.trim_start_matches('0');
+        if digits.contains('.') {
+            digits = digits.trim_end_matches('0').trim_end_matches('.');
+        }
+        let digits = if digits.is_empty() || digits.starts_with('.') { format!("0{}", digits) } else { digits.to_string() };
+
+        let shown = T::from_f64(value).to_string();
+        (digits != shown).then(|| {
+            let literal: String = literal.iter().collect();
+            format!("Warning: {} cannot be represented exactly and was rounded to {}.", literal, shown)
+        })
+    }
+
+    pub fn memory_add(&mut self) {
+        self.memory = T::from_f64(self.memory + self.ans).to_f64();
+    }
+
+    pub fn memory_clear(&mut self) {
+        self.memory = 0.0;
+    }
+
+    /// Built-in constants, `ans` and `MR` always win, so defining e.g. `pi` has no effect. User
+    /// constants shadow variables of the same name and cannot be reassigned with `=`.
+    pub fn define_constant(&mut self, name: &str, value: T) {
+        self.constants.insert(name.to_string(), value.to_f64());
+    }
+
+    /// Adds a left-associative binary operator. Precedence uses the built-in scale, where `+` is 9,
+    /// `*` is 10 and `^` is 12, and must be at least 1. Symbols the tokenizer already understands
+    /// (digits, letters, whitespace, brackets, separators and the built-in operators) cannot be
+    /// redefined, and are rejected as an invalid expression along with a precedence of 0.
+    pub fn register_operator(&mut self, symbol: char, precedence: u8, f: BinaryFn<T>) -> Result<(), CalculatorError> {
+        if precedence == 0 || self.is_reserved(symbol) {
+            return Err(CalculatorError::InvalidExpression);
+        }
+        self.operators.insert(symbol, (precedence, BinaryOp::Registered(f)));
+        Ok(())
+    }
+
+    fn is_reserved(&self, symbol: char) -> bool {
+        let built_in = OPERATOR_TABLE
+            .iter()
+            .any(|&(written, op, ..)| op == symbol || written.contains(symbol));
+        built_in
+            || symbol.is_alphanumeric()
+            || symbol.is_whitespace()
+            || symbol == self.decimal_sep
+            || Calculator::normalize_char(symbol) != symbol
+            || "()[]{}.,#$_=".contains(symbol)
+    }
+
+    fn resolve(&self, name: &str) -> Option<f64> {
+        self.resolve_constant(name).or(self.variables.get(name).copied())
+    }
+
+    fn resolve_constant(&self, name: &str) -> Option<f64> {
+        match name {
+            "ans" => Some(self.ans),
+            "MR" => Some(self.memory),
+            _ if name.starts_with('$') => self.history_entry(name),
+            _ => Calculator::constant(name)
+                .map(|value| T::from_f64(value).to_f64())
+                .or(self.constants.get(name).copied()),
+        }
+    }
+
+    // `$1` is the first result this calculator produced, `$2` the second, and so on.
+    fn history_entry(&self, name: &str) -> Option<f64> {
+        let n = name.strip_prefix('$')?.parse::<usize>().ok()?;
+        self.history.get(n.checked_sub(1)?).copied()
+    }
+
+    fn check_len(&self) -> Result<(), CalculatorError> {
+        match self.max_len {
+            Some(max_len) if self.expr.len() > max_len => Err(CalculatorError::ExpressionTooLong { max_len }),
+            _ => Ok(()),
+        }
+    }
+
+    // Rounds a computed value to the calculator's number type, reporting values that only
+    // overflow once narrowed.
+    fn narrow(&self, value: f64) -> Result<f64, CalculatorError> {
+        if self.integer_mode && value.is_finite() {
+            if value.fract() != 0.0 {
+                return Err(CalculatorError::InvalidExpression);
+            }
+            if value.abs() >= MAX_EXACT_INTEGER {
+                return Err(CalculatorError::Overflow);
+            }
+        }
+        let narrowed = T::from_f64(value).to_f64();
+        if narrowed.is_infinite() && value.is_finite() {
+            return Err(CalculatorError::Overflow);
+        }
+        Ok(narrowed)
+    }
+
+    /// In percent mode a `%` following an operand divides it by 100, so `200 + 10%` is `200.1`
+    /// and `200 * 50%` is `100`. Modulo is unavailable while this mode is enabled, so `10 % 3` is
+    /// an invalid expression rather than `10% * 3`.
+    pub fn with_percent_mode(mut self) -> Self {
+        self.percent_mode = true;
+        self
+    }
+
+    fn number_format(&self) -> NumberFormat {
+        NumberFormat {
+            comma_grouping: self.comma_grouping,
+            decimal_sep: self.decimal_sep,
+            sexagesimal: self.sexagesimal,
+            integer_only: self.integer_only,
+        }
+    }
+
+    pub(crate) fn apply_binary(&self, op: char, a: f64, b: f64) -> Result<f64, CalculatorError> {
+        if matches!(op, '/' | '⫽' | '%') && b.abs() <= self.zero_epsilon {
+            return match self.div_by_zero {
+                DivByZeroPolicy::Error => Err(CalculatorError::ZeroDivision { dividend: a }),
+                DivByZeroPolicy::Infinity if op != '%' && a != 0.0 && !a.is_nan() => Ok(a.signum() * f64::INFINITY),
+                DivByZeroPolicy::Infinity | DivByZeroPolicy::Nan => Ok(f64::NAN),
+            };
+        }
+        let out = match self.operators.get(&op) {
+            Some((_, BinaryOp::BuiltIn(_))) if self.integer_mode && matches!(op, '+' | '-' | '*' | '/') => {
+                Calculator::apply_integer(op, a, b)
+            }
+            Some(&(_, BinaryOp::BuiltIn(f))) => f(a, b).and_then(|out| Calculator::check_overflow(out, &[a, b])),
+            Some(&(_, BinaryOp::Registered(f))) => {
+                Calculator::check_overflow(f(T::from_f64(a), T::from_f64(b)).to_f64(), &[a, b])
+            }
+            None => Err(CalculatorError::InvalidExpression),
+        }?;
+        self.narrow(out)
+    }
+
+    pub(crate) fn round(&self, value: f64) -> T {
+        let Some(digits) = self.precision else {
+            return T::from_f64(value);
+        };
+        let scale = 10f64.powi(digits.min(i32::MAX as usize) as i32);
+        let rounded = (value * scale).round() / scale;
+        T::from_f64(if rounded.is_finite() { rounded } else { value })
+    }
+
+    fn is_registered(&self, symbol: char) -> bool {
+        matches!(self.operators.get(&symbol), Some((_, BinaryOp::Registered(_))))
+    }
+
+    fn operator_precedence(&self, op: char) -> u8 {
+        self.operators.get(&op).map_or(Calculator::precedence(op), |&(precedence, _)| precedence)
+    }
+
+    fn normalized_chars(&self) -> Vec<char> {
+        self.expr.chars().map(Calculator::normalize_char).collect()
+    }
+
+    // Finds a leading `name =` or compound `name += ` (also `-=`, `*=`, `/=`), returning the name,
+    // where the right-hand side starts and the compound operator.
+    fn assignment(&self) -> Option<(String, usize, Option<char>)> {
+        let chars = self.normalized_chars();
+        let mut i = 0;
+
+        while i < chars.len() && chars[i].is_whitespace() {
+            i += 1;
+        }
+        let start = i;
+        if i >= chars.len() || !chars[i].is_alphabetic() {
+            return None;
+        }
+        while i < chars.len() && chars[i].is_alphanumeric() {
+            i += 1;
+        }
+        let name: String = chars[start..i].iter().collect();
+
+        while i < chars.len() && chars[i].is_whitespace() {
+            i += 1;
+        }
+        let compound = match (chars.get(i), chars.get(i + 1)) {
+            (Some(&op @ ('+' | '-' | '*' | '/')), Some('=')) => Some(op),
+            _ => None,
+        };
+        if compound.is_some() {
+            i += 1;
+        }
+        if chars.get(i) != Some(&'=') || chars.get(i + 1) == Some(&'=') {
+            return None;
+        }
+        if name == "ans"
+            || name == "MR"
+            || Calculator::constant(&name).is_some()
+            || self.constants.contains_key(&name)
+            || FUNCTIONS.contains(&name.as_str())
+        {
+            return None;
+        }
+
+        Some((name, i + 1, compound))
+    }
+
+    // Returns the indices of the tokens that end a bracketed group, for `parse_ast`.
+    fn tokenizer(&mut self, keep_variables: bool) -> Result<Vec<usize>, CalculatorError> {
+        self.tokens.clear();
+        let mut stream = TokenStream::new(self, keep_variables);
+        let tokens = stream.by_ref().collect::<Result<Vec<_>, _>>();
+        let warnings = std::mem::take(&mut stream.lexer.warnings);
+        let groups = std::mem::take(&mut stream.groups);
+        self.warnings = warnings;
+        self.tokens = tokens?;
+        Ok(groups)
+    }
+
+    /// Describes each precedence decision the tokenizer makes, e.g. why `3 * 4` is computed before
+    /// the addition in `2 + 3 * 4`.
+    pub fn explain(&self) -> Result<Vec<String>, CalculatorError> {
+        let mut stream = TokenStream::new(self, false);
+        stream.notes = Some(Vec::new());
+        for token in stream.by_ref() {
+            token?;
+        }
+        Ok(stream.notes.unwrap_or_default())
+    }
+
+    pub fn original_expr(&self) -> &str {
+        &self.expr
+    }
+
+    /// The expression as the tokenizer reads it: digits and full-width characters normalized, a
+    /// leading formula `=` dropped, implied multiplications written out and `x += 5` expanded to
+    /// `x = x + (5)`. Anything after a syntax error is left as it was.
+    pub fn normalized_expr(&self) -> String {
+        let mut stream = TokenStream::new(self, true);
+        let start = stream.lexer.i;
+        while let Some(Ok(_)) = stream.next() {}
+
+        let chars = std::mem::take(&mut stream.lexer.chars);
+        let written = |range: std::ops::Range<usize>| {
+            let mut out = String::new();
+            for i in range {
+                if stream.implied.contains(&i) {
+                    out.push('*');
+                }
+                out.push(chars[i]);
+            }
+            out
+        };
+        match self.assignment() {
+            // The stream reads `x += 4 # note` as `x +( 4 # note` and a closing `)` it appended,
+            // which is written back as `x = x + (4) # note`.
+            Some((name, rhs, Some(op))) => {
+                let text = written(rhs..chars.len() - 2);
+                let (value, comment) = text.split_at(text.find('#').unwrap_or(text.len()));
+                let comment = if comment.is_empty() { String::new() } else { format!(" {}", comment.trim_end()) };
+                format!("{} = {} {} ({}){}", name, name, op, value.trim(), comment)
+            }
+            Some(_) => written(0..chars.len()),
+            None => written(start..chars.len()),
+        }
+    }
+
+    /// The lexemes the tokenizer works from, so `2<=3` is a number, the operator `<=` and a number.
+    pub fn lex(&self) -> Result<Vec<Lexeme>, CalculatorError> {
+        self.check_len()?;
+        TokenStream::new(self, false).lexer.map(|lexeme| lexeme.map(|(_, lexeme)| lexeme)).collect()
+    }
+
+    pub fn stream_tokens(&self) -> impl Iterator<Item = Result<Token, CalculatorError>> + '_ {
+        TokenStream::new(self, false).map(|token| token.map(|token| Token::from(&token)))
+    }
+
+    fn postfix_tokenizer(&mut self) -> Result<(), CalculatorError> {
+        self.tokens.clear();
+        self.warnings.clear();
+        self.check_len()?;
+        let mut depth = 0usize;
+
+        let words = self.expr
+            .lines()
+            .flat_map(|line| line.split('#').next().unwrap_or_default().split_whitespace());
+
+        for word in words {
+            let offset = word.as_ptr() as usize - self.expr.as_ptr() as usize;
+            let position = self.expr[..offset].chars().count();
+            let chars: Vec<char> = word
+                .chars()
+                .filter(|&c| !Calculator::is_ignorable(c))
+                .map(Calculator::normalize_char)
+                .collect();
+            if chars.is_empty() {
+                continue;
+            }
+            let word: String = chars.iter().collect();
+            let word = word.as_str();
+
+            let token = if word == "^" && self.caret_is_xor {
+                RPNToken::Operation('⊻')
+            } else if let Some(op) = Calculator::postfix_operator(word) {
+                RPNToken::Operation(op)
+            } else if chars.len() == 1 && self.is_registered(chars[0]) {
+                RPNToken::Operation(chars[0])
+            } else if word == "-inf" {
+                RPNToken::Number(f64::NEG_INFINITY)
+            } else if chars[0].is_ascii_digit()
+                || chars[0] == self.decimal_sep
+                || (chars[0] == '-' && chars.len() > 1)
+            {
+                let is_negative = chars[0] == '-';
+                let mut i = is_negative as usize;
+                let number = Calculator::parse_number(&chars, &mut i, is_negative, self.number_format())?;
+                if i != chars.len() {
+                    return Err(CalculatorError::InvalidExpression);
+                }
+                if let Some(warning) = self.precision_warning(&chars[is_negative as usize..], number.abs()) {
+                    self.warnings.push(warning);
+                }
+                RPNToken::Number(self.narrow(number)?)
+            } else if let Some(value) = self.resolve(word) {
+                RPNToken::Number(self.narrow(value)?)
+            } else if word.starts_with('$') {
+                return Err(CalculatorError::UnboundVariable { name: word.to_string() });
+            } else if let Some((name, argc)) = Calculator::variadic_call(word) {
+                RPNToken::Function(name.to_string(), argc)
+            } else if FUNCTIONS.contains(&word) {
+                RPNToken::Function(word.to_string(), Calculator::function_arity(word).unwrap_or(depth))
+            } else {
+                let token = word.chars().map(Calculator::describe_char).collect();
+                return Err(CalculatorError::UnsupportedToken { position, token });
+            };
+            depth = depth.saturating_sub(Calculator::arity(&token)) + 1;
+            self.tokens.push(token);
+        }
+
+        if self.tokens.is_empty() {
+            return Err(CalculatorError::EmptyExpression);
+        }
+        Ok(())
+    }
+
+    fn validate_tokens(&self) -> Result<(), CalculatorError> {
+        let mut depth = 0usize;
+
+        for token in &self.tokens {
+            depth = depth.checked_sub(Calculator::arity(token)).ok_or(CalculatorError::InvalidExpression)? + 1;
+        }
+
+        if depth != 1 {
+            return Err(CalculatorError::InvalidExpression);
+        }
+        Ok(())
+    }
+
+    // Collapses every subexpression whose operands are all numbers into a single number, so a
+    // fully constant expression becomes one token. Steps that would fail are left in place for
+    // `eval` to report. Expects tokens that already passed `validate_tokens`.
+    fn fold_constants(&mut self) {
+        let mut stack: Vec<Vec<RPNToken>> = Vec::new();
+
+        for token in std::mem::take(&mut self.tokens) {
+            let operands = stack.split_off(stack.len().saturating_sub(Calculator::arity(&token)));
+            let values: Option<Vec<f64>> = operands
+                .iter()
+                .map(|operand| match operand.as_slice() {
+                    [RPNToken::Number(n)] => Some(*n),
+                    _ => None,
+                })
+                .collect();
+
+            match values.and_then(|values| self.fold_token(&token, &values)) {
+                Some(n) => stack.push(vec![RPNToken::Number(n)]),
+                None => {
+                    let mut group: Vec<RPNToken> = operands.into_iter().flatten().collect();
+                    group.push(token);
+                    stack.push(group);
+                }
+            }
+        }
+
+        self.tokens = stack.into_iter().flatten().collect();
+    }
+
+    fn fold_token(&self, token: &RPNToken, args: &[f64]) -> Option<f64> {
+        match (token, args) {
+            (RPNToken::Number(n), []) => Some(*n),
+            (RPNToken::Operation(':'), [c, a, b]) => Some(if *c != 0.0 { *a } else { *b }),
+            _ => self.apply_token(token, args).ok(),
+        }
+    }
+
+    fn apply_token(&self, token: &RPNToken, args: &[f64]) -> Result<f64, CalculatorError> {
+        match (token, args) {
+            (RPNToken::Operation(op), [x]) if Calculator::is_unary(*op) => self.apply_unary_op(*op, *x),
+            (RPNToken::Operation(op), [a, b]) => self.apply_binary(*op, *a, *b),
+            (RPNToken::Function(name, _), args) => self.apply_call(name, args),
+            _ => Err(CalculatorError::InvalidExpression),
+        }
+    }
+
+    pub(crate) fn apply_unary_op(&self, op: char, x: f64) -> Result<f64, CalculatorError> {
+        self.narrow(Calculator::apply_unary(op, x)?)
+    }
+
+    pub(crate) fn apply_call(&self, name: &str, args: &[f64]) -> Result<f64, CalculatorError> {
+        self.narrow(Calculator::apply_function(name, args, self.angle_mode)?)
+    }
+
+    /// Uses the stored tokens when there are any, and otherwise tokenizes the expression to
+    /// check. An expression that is not well formed is not constant.
+    pub fn is_constant(&self) -> bool {
+        if !self.tokens.is_empty() {
+            return !self.tokens.iter().any(|token| matches!(token, RPNToken::Variable(_)));
+        }
+        let mut depth = 0usize;
+        for token in TokenStream::new(self, true) {
+            match token {
+                Ok(RPNToken::Variable(_)) | Err(_) => return false,
+                Ok(token) => match depth.checked_sub(Calculator::arity(&token)) {
+                    Some(rest) => depth = rest + 1,
+                    None => return false,
+                },
+            }
+        }
+        depth == 1
+    }
+
+    pub fn tokens(&self) -> impl Iterator<Item = Token> + '_ {
+        self.tokens.iter().map(Token::from)
+    }
+
+    pub fn to_rpn(&mut self) -> Result<String, CalculatorError> {
+        self.tokenizer(false)?;
+        Ok(self.tokens.iter().map(|token| token.to_string()).collect::<Vec<_>>().join(" "))
+    }
+
+    /// Chained comparisons written without brackets are expanded, so `3 > 2 > 1` is `3 > 2 && 2 > 1`
+    /// and evaluates to 1, while `(3 > 2) > 1` compares the result of `3 > 2` with 1. `eval` reads
+    /// every comparison left to right instead, giving 0 for both.
+    pub fn parse_ast(&mut self) -> Result<Expr, CalculatorError> {
+        let groups = self.tokenizer(false)?;
+        // Each node remembers whether it was written in brackets.
+        let mut nodes: Vec<(Expr, bool)> = Vec::new();
+        let pop = |nodes: &mut Vec<(Expr, bool)>| nodes.pop().ok_or(CalculatorError::InvalidExpression);
+
+        for (i, token) in self.tokens.iter().enumerate() {
+            let node = match token {
+                RPNToken::Operation(op) if Calculator::is_unary(*op) => {
+                    let (operand, _) = pop(&mut nodes)?;
+                    Expr::UnaryOp(*op, Box::new(operand))
+                }
+                RPNToken::Operation(':') => {
+                    let (otherwise, _) = pop(&mut nodes)?;
+                    let (then, _) = pop(&mut nodes)?;
+                    let (condition, _) = pop(&mut nodes)?;
+                    Expr::Cond(Box::new(condition), Box::new(then), Box::new(otherwise))
+                }
+                RPNToken::Operation(op) => {
+                    let (rhs, _) = pop(&mut nodes)?;
+                    let (lhs, grouped) = pop(&mut nodes)?;
+                    Expr::binary(*op, lhs, rhs, grouped)
+                }
+                RPNToken::Function(name, argc) => {
+                    let start = nodes.len().checked_sub(*argc).ok_or(CalculatorError::InvalidExpression)?;
+                    Expr::Call(name.clone(), nodes.split_off(start).into_iter().map(|(arg, _)| arg).collect())
+                }
+                RPNToken::Number(n) => Expr::Num(*n),
+                RPNToken::Variable(_) => return Err(CalculatorError::InvalidExpression),
+            };
+            nodes.push((node, groups.binary_search(&i).is_ok()));
+        }
+
+        match (nodes.pop(), nodes.is_empty()) {
+            (Some((root, _)), true) => Ok(root),
+            _ => Err(CalculatorError::InvalidExpression),
+        }
+    }
+
+    pub fn eval(&mut self) -> Result<T, CalculatorError> {
+        self.eval_traced(None)
+    }
+
+    /// Measures only tokenizing and evaluating, for comparing the cost of different expressions.
+    pub fn eval_timed(&mut self) -> (Result<T, CalculatorError>, Duration) {
+        let start = Instant::now();
+        let result = self.eval();
+        (result, start.elapsed())
+    }
+
+    pub fn eval_verbose(&mut self) -> Result<(T, Vec<String>), CalculatorError> {
+        let mut trace = Vec::new();
+        let value = self.eval_traced(Some(&mut trace))?;
+        Ok((value, trace))
+    }
+
+    /// Tokenizes the expression once, leaving names that are not constants as variables to be
+    /// bound by `eval_with`. Assigned variables are looked up when no binding is given. Constant
+    /// subexpressions are folded ahead of time.
+    pub fn parse(&mut self) -> Result<(), CalculatorError> {
+        self.tokenizer(true)?;
+        self.validate_tokens()?;
+        self.fold_constants();
+        self.tokenized = true;
+        Ok(())
+    }
+
+    /// Checks the expression without evaluating it, carrying on past each problem so that every
+    /// unsupported token and unbalanced parenthesis is reported in one pass. Once a problem has
+    /// been found the rest of the report is best effort. Runtime errors such as division by zero
+    /// are not looked for.
+    pub fn validate(&self) -> Vec<CalculatorError> {
+        if let Err(e) = self.check_len() {
+            return vec![e];
+        }
+        let mut stream = TokenStream::new(self, false);
+        let mut errors: Vec<CalculatorError> = stream.failed.take().into_iter().collect();
+        let mut depth = 0usize;
+        let mut balanced = true;
+
+        while !stream.done {
+            let start = stream.lexer.i;
+            match stream.step() {
+                Ok(()) => {}
+                // Earlier errors only left the expression looking empty, as with `foo + bar)`.
+                Err(CalculatorError::EmptyExpression) if !errors.is_empty() => {}
+                Err(e) => {
+                    errors.push(e);
+                    if stream.lexer.i == start {
+                        stream.lexer.i += 1;
+                    }
+                }
+            }
+            stream.emitted += stream.output.len();
+            for token in stream.output.drain(..) {
+                match depth.checked_sub(Calculator::arity(&token)) {
+                    Some(rest) => depth = rest + 1,
+                    None => balanced = false,
+                }
+            }
+        }
+
+        if errors.is_empty() && (!balanced || depth != 1) {
+            errors.push(CalculatorError::InvalidExpression);
+        }
+        errors
+    }
+
+    pub fn eval_with(&self, vars: &HashMap<String, T>) -> Result<T, CalculatorError> {
+        if self.tokens.is_empty() {
+            return Err(CalculatorError::EmptyExpression);
+        }
+        self.eval_tokens(self.tokens.iter().map(Ok), vars, None, &mut 0)
+    }
+
+    fn eval_traced(&mut self, trace: Option<&mut Vec<String>>) -> Result<T, CalculatorError> {
+        let vars = HashMap::new();
+        let mut ops = 0;
+        self.op_count = 0;
+        let value = if self.postfix {
+            self.postfix_tokenizer()?;
+            self.eval_tokens(self.tokens.iter().map(Ok), &vars, trace, &mut ops)
+        } else if self.tokenized {
+            self.eval_tokens(self.tokens.iter().map(Ok), &vars, trace, &mut ops)
+        } else {
+            let mut stream = TokenStream::new(self, false);
+            let value = self.eval_tokens(&mut stream, &vars, trace, &mut ops);
+            let warnings = std::mem::take(&mut stream.lexer.warnings);
+            self.warnings = warnings;
+            value
+        };
+        self.op_count = ops;
+        let value = value?;
+
+        if let Some((name, _, _)) = self.assignment() {
+            self.variables.insert(name, value.to_f64());
+        }
+        self.ans = value.to_f64();
+        self.history.push(self.ans);
+        Ok(value)
+    }
+
+    fn eval_tokens<B: Borrow<RPNToken>>(
+        &self,
+        tokens: impl Iterator<Item = Result<B, CalculatorError>>,
+        vars: &HashMap<String, T>,
+        mut trace: Option<&mut Vec<String>>,
+        ops: &mut usize
+    ) -> Result<T, CalculatorError> {
+        // Failed steps stay on the stack as errors, so a conditional can discard the branch it
+        // does not select. Any other step that consumes an error passes it on.
+        let mut result: Vec<Result<f64, CalculatorError>> = Vec::new();
+
+        for token in tokens {
+            let token = token?;
+            let token = token.borrow();
+            let start = match token {
+                RPNToken::Number(n) => {
+                    result.push(Ok(*n));
+                    continue;
+                }
+                RPNToken::Variable(name) => {
+                    let value = vars.get(name).map(|value| value.to_f64()).or(self.variables.get(name).copied());
+                    let value = value.ok_or_else(|| CalculatorError::UnboundVariable { name: name.clone() });
+                    result.push(value.and_then(|value| self.narrow(value)));
+                    continue;
+                }
+                _ => result.len().checked_sub(Calculator::arity(token)).ok_or(CalculatorError::InvalidExpression)?,
+            };
+            let operands = result.split_off(start);
+
+            if let RPNToken::Operation(':') = token {
+                let [condition, then, otherwise]: [Result<f64, CalculatorError>; 3] =
+                    operands.try_into().map_err(|_| CalculatorError::InvalidExpression)?;
+                let out = condition.clone().and_then(|c| if c != 0.0 { then } else { otherwise });
+                if let (Some(log), Ok(c), Ok(n)) = (trace.as_deref_mut(), &condition, &out) {
+                    log.push(format!("{} {} = {}", c, token, n));
+                }
+                result.push(out);
+                continue;
+            }
+
+            let args = match operands.into_iter().collect::<Result<Vec<f64>, _>>() {
+                Ok(args) => args,
+                Err(e) => {
+                    result.push(Err(e));
+                    continue;
+                }
+            };
+            let out = self.apply_token(token, &args);
+            *ops += 1;
+
+            if let (Some(log), Ok(n)) = (trace.as_deref_mut(), &out) {
+                let operands: Vec<String> = args.iter().map(|n| n.to_string()).collect();
+                log.push(format!("{} {} = {}", operands.join(" "), token, n));
+            }
+            result.push(out);
+        }
+
+        match (result.pop(), result.is_empty()) {
+            (Some(value), true) => value.map(|value| self.round(value)),
+            _ => Err(CalculatorError::InvalidExpression),
+        }
+    }
+
+    /// Reads this one expression as postfix, leaving the calculator's own mode as it was.
+    pub fn eval_postfix(&mut self) -> Result<T, CalculatorError> {
+        let postfix = std::mem::replace(&mut self.postfix, true);
+        let value = self.eval();
+        self.postfix = postfix;
+        value
+    }
+
+    pub fn eval_typed(&mut self) -> Result<CalcValue, CalculatorError> {
+        self.eval().map(|value| CalcValue::from(value.to_f64()))
+    }
+
+    pub fn eval_all(&mut self) -> Vec<Result<T, CalculatorError>> {
+        // The limit applies to the whole input, not to each statement.
+        if let Err(e) = self.check_len() {
+            return vec![Err(e)];
+        }
+        let expr = std::mem::take(&mut self.expr);
+        // Comments are dropped first, so a `;` inside one does not start a statement.
+        let code: Vec<&str> = expr.lines().map(|line| line.split('#').next().unwrap_or_default()).collect();
+        let results = code
+            .join("\n")
+            .split(';')
+            .filter(|segment| !segment.trim().is_empty())
+            .map(|segment| {
+                self.set_expr(segment.to_string());
+                self.eval()
+            })
+            .collect();
+
+        self.set_expr(expr);
+        results
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Lexeme {
+    Number(f64),
+    Identifier(String),
+    Operator(String),
+    Open(char),
+    Close(char),
+    Comma,
+}
+
+// Splits the input into numbers, names, operators and brackets, skipping whitespace and
+// comments. Numbers are read without a sign; whether a `-` negates a literal depends on what
+// precedes it, which only the shunting-yard pass knows.
+struct Lexer<'a, T: Number> {
+    calculator: &'a Calculator<T>,
+    chars: Vec<char>,
+    i: usize,
+    warnings: Vec<String>,
+}
+
+impl<T: Number> Lexer<'_, T> {
+    fn operator(&self) -> Option<String> {
+        let rest = &self.chars[self.i..];
+        let written = OPERATOR_TABLE
+            .iter()
+            .map(|&(written, ..)| written)
+            .filter(|written| {
+                let mut next = rest.iter();
+                written.chars().all(|c| next.next() == Some(&c))
+            })
+            .max_by_key(|written| written.len());
+        match written {
+            Some(written) => Some(written.to_string()),
+            None if self.calculator.is_registered(rest[0]) => Some(rest[0].to_string()),
+            None => None,
+        }
+    }
+}
+
+impl<T: Number> Iterator for Lexer<'_, T> {
+    type Item = Result<(usize, Lexeme), CalculatorError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let format = self.calculator.number_format();
+        loop {
+            let &c = self.chars.get(self.i)?;
+            let i = self.i;
+            let chars = &self.chars;
+
+            if c.is_whitespace() || Calculator::is_ignorable(c) {
+                self.i += 1;
+                continue;
+            }
+
+            if c == '#' {
+                self.i = chars[i..].iter().position(|&c| c == '\n').map_or(chars.len(), |offset| i + offset);
+                continue;
+            }
+
+            let starts_decimal = c == format.decimal_sep
+                && (c != ',' || chars.get(i + 1).is_some_and(|c| c.is_ascii_digit()));
+            if c.is_ascii_digit() || starts_decimal || c == '_' {
+                let number = Calculator::parse_number(&self.chars, &mut self.i, false, format);
+                if let Ok(number) = number
+                    && let Some(warning) = self.calculator.precision_warning(&self.chars[i..self.i], number)
+                {
+                    self.warnings.push(warning);
+                }
+                return Some(number.map(|number| (i, Lexeme::Number(number))));
+            }
+
+            if c == '$' || c.is_alphabetic() {
+                let end = chars[i + 1..]
+                    .iter()
+                    .position(|&next| if c == '$' { !next.is_ascii_digit() } else { !next.is_alphanumeric() })
+                    .map_or(chars.len(), |offset| i + 1 + offset);
+                self.i = end;
+                return Some(Ok((i, Lexeme::Identifier(chars[i..end].iter().collect()))));
+            }
+
+            let lexeme = match c {
+                '(' | '[' | '{' => Lexeme::Open(c),
+                ')' | ']' | '}' => Lexeme::Close(c),
+                ',' => Lexeme::Comma,
+                _ => match self.operator() {
+                    Some(op) => {
+                        self.i += op.chars().count() - 1;
+                        Lexeme::Operator(op)
+                    }
+                    None => {
+                        self.i += 1;
+                        return Some(Err(CalculatorError::UnsupportedToken {
+                            position: i,
+                            token: Calculator::describe_char(c),
+                        }));
+                    }
+                },
+            };
+            self.i += 1;
+            return Some(Ok((i, lexeme)));
+        }
+    }
+}
+
+// Runs the shunting-yard algorithm lazily over the lexer's output, reading just enough input
+// to yield the next RPN token, so callers can evaluate an expression without collecting its
+// tokens first.
+struct TokenStream<'a, T: Number> {
+    calculator: &'a Calculator<T>,
+    lexer: Lexer<'a, T>,
+    pending: Option<Result<(usize, Lexeme), CalculatorError>>,
+    keep_variables: bool,
+    operations: Vec<RPNToken>,
+    open_parens: Vec<(usize, char, Option<usize>)>,
+    previous: Previous,
+    output: VecDeque<RPNToken>,
+    notes: Option<Vec<String>>,
+    // The loosest operator inside the group just closed, for explaining what its brackets did
+    // once the operator that follows them is known, and the precedence that operator must beat
+    // to take the group as its operand.
+    bracketed: Option<(char, u8)>,
+    implied: Vec<usize>,
+    // Indices of the tokens that end a bracketed group, in order, so `(1 < 2) < 3` can be told
+    // apart from `1 < 2 < 3` once the brackets are gone.
+    groups: Vec<usize>,
+    failed: Option<CalculatorError>,
+    emitted: usize,
+    done: bool,
+}
+
+impl<'a, T: Number> TokenStream<'a, T> {
+    fn new(calculator: &'a Calculator<T>, keep_variables: bool) -> Self {
+        let mut chars = calculator.normalized_chars();
+        let mut failed = calculator.check_len().err();
+        // `x += 5` is read as `x +( 5` followed by a `)` on a line of its own, which keeps error
+        // positions pointing at the original input.
+        let i = match calculator.assignment() {
+            Some((name, start, Some(_))) => {
+                if calculator.variable(&name).is_none() {
+                    failed.get_or_insert(CalculatorError::InvalidExpression);
+                }
+                chars[start - 1] = '(';
+                chars.extend(['\n', ')']);
+                0
+            }
+            Some((_, start, None)) => start,
+            None => Calculator::formula_start(&chars),
+        };
+        TokenStream {
+            calculator,
+            lexer: Lexer { calculator, chars, i, warnings: Vec::new() },
+            pending: None,
+            keep_variables,
+            operations: Vec::new(),
+            open_parens: Vec::new(),
+            previous: Previous::Operator,
+            output: VecDeque::new(),
+            notes: None,
+            bracketed: None,
+            implied: Vec::new(),
+            groups: Vec::new(),
+            failed,
+            emitted: 0,
+            done: false,
+        }
+    }
+
+    fn next_lexeme(&mut self) -> Option<Result<(usize, Lexeme), CalculatorError>> {
+        self.pending.take().or_else(|| self.lexer.next())
+    }
+
+    fn peek_lexeme(&mut self) -> Option<&Lexeme> {
+        if self.pending.is_none() {
+            self.pending = self.lexer.next();
+        }
+        match &self.pending {
+            Some(Ok((_, lexeme))) => Some(lexeme),
+            _ => None,
+        }
+    }
+
+    // Records where a `*` was implied, so `2(3)` can be shown as `2*(3)`.
+    fn imply_multiplication(&mut self, position: usize) {
+        self.implied.push(position);
+        self.push_operator('*');
+    }
+
+    fn push_operator(&mut self, op: char) {
+        let right_assoc = Calculator::is_right_associative(op);
+        let precedence = self.calculator.operator_precedence(op);
+        if let Some((inner, floor)) = self.bracketed.take()
+            && self.previous == Previous::Close
+            && precedence > floor
+        {
+            self.explain_brackets(inner, op);
+        }
+        while let Some(&RPNToken::Operation(top)) = self.operations.last() {
+            let top_precedence = self.calculator.operator_precedence(top);
+            if top_precedence > precedence || (!right_assoc && top_precedence == precedence) {
+                self.explain(top, op, top_precedence, precedence);
+                self.operations.pop();
+                self.output.push_back(RPNToken::Operation(top));
+            } else {
+                if top != '(' {
+                    self.explain(top, op, top_precedence, precedence);
+                }
+                break;
+            }
+        }
+        self.operations.push(RPNToken::Operation(op));
+    }
+
+    fn explain(&mut self, earlier: char, later: char, earlier_precedence: u8, later_precedence: u8) {
+        let Some(notes) = self.notes.as_mut() else {
+            return;
+        };
+        let (first, second) = (Calculator::operator_name(earlier), Calculator::operator_name(later));
+        let note = match earlier_precedence.cmp(&later_precedence) {
+            std::cmp::Ordering::Greater =>
+                format!("{} binds tighter than {}, so the {} is computed first.", first, second, first),
+            std::cmp::Ordering::Less =>
+                format!("{} binds tighter than {}, so the {} is computed first.", second, first, second),
+            std::cmp::Ordering::Equal if Calculator::is_right_associative(later) =>
+                format!("{} groups right to left, so the right-hand {} is computed first.", first, first),
+            std::cmp::Ordering::Equal if earlier == later =>
+                format!("{} groups left to right, so the left-hand {} is computed first.", first, first),
+            std::cmp::Ordering::Equal =>
+                format!("{} and {} have the same precedence, so they are computed left to right.", first, second),
+        };
+        notes.push(note);
+    }
+
+    // Notes brackets that change the order of evaluation, as in `(2 + 3) * 4` or `4 * (2 + 3)`.
+    fn explain_brackets(&mut self, inner: char, outer: char) {
+        let Some(notes) = self.notes.as_mut() else {
+            return;
+        };
+        if self.calculator.operator_precedence(outer) > self.calculator.operator_precedence(inner) {
+            notes.push(format!(
+                "the brackets make the {} happen before the {}, which would otherwise be computed first.",
+                Calculator::operator_name(inner),
+                Calculator::operator_name(outer)
+            ));
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), CalculatorError> {
+        self.done = true;
+
+        if let Some(&(position, _, _)) = self.open_parens.last() {
+            return Err(CalculatorError::MismatchedParantheses { position });
+        }
+
+        while let Some(op) = self.operations.pop() {
+            if let RPNToken::Operation('?') = op {
+                return Err(CalculatorError::InvalidExpression);
+            }
+            self.output.push_back(op);
+        }
+
+        if self.emitted == 0 && self.output.is_empty() {
+            return Err(CalculatorError::EmptyExpression);
+        }
+        Ok(())
+    }
+
+    fn push_number(&mut self, number: f64) -> Result<(), CalculatorError> {
+        self.output.push_back(RPNToken::Number(self.calculator.narrow(number)?));
+        self.previous = Previous::Number;
+        Ok(())
+    }
+
+    fn step(&mut self) -> Result<(), CalculatorError> {
+        let Some(lexeme) = self.next_lexeme() else {
+            return self.finish();
+        };
+        let (i, lexeme) = lexeme?;
+
+        if self.previous == Previous::Percent
+            && matches!(lexeme, Lexeme::Number(_) | Lexeme::Identifier(_) | Lexeme::Open(_))
+        {
+            return Err(CalculatorError::InvalidExpression);
+        }
+
+        match lexeme {
+            Lexeme::Number(number) => {
+                if self.previous == Previous::Close {
+                    self.imply_multiplication(i);
+                }
+                self.push_number(number)?;
+            }
+            Lexeme::Identifier(name) => self.identifier(i, name)?,
+            Lexeme::Open(c) => {
+                if self.previous != Previous::Operator {
+                    self.imply_multiplication(i);
+                }
+                let is_call = matches!(self.operations.last(), Some(RPNToken::Function(..)));
+                self.open_parens.push((i, c, is_call.then_some(0)));
+                self.operations.push(RPNToken::Operation('('));
+                self.previous = Previous::Operator;
+            }
+            Lexeme::Close(c) => self.close(i, c)?,
+            Lexeme::Comma => {
+                let Some((_, _, Some(commas))) = self.open_parens.last_mut() else {
+                    return Err(CalculatorError::UnsupportedToken { position: i, token: ",".to_string() });
+                };
+                if self.previous == Previous::Operator {
+                    return Err(CalculatorError::InvalidExpression);
+                }
+                *commas += 1;
+                while let Some(top) = self.operations.pop() {
+                    match top {
+                        RPNToken::Operation('(') => {
+                            self.operations.push(top);
+                            break;
+                        }
+                        RPNToken::Operation('?') => return Err(CalculatorError::InvalidExpression),
+                        _ => self.output.push_back(top),
+                    }
+                }
+                self.previous = Previous::Operator;
+            }
+            Lexeme::Operator(op) => self.operator(i, &op)?,
+        }
+        Ok(())
+    }
+
+    fn identifier(&mut self, i: usize, name: String) -> Result<(), CalculatorError> {
+        if self.previous != Previous::Operator {
+            self.imply_multiplication(i);
+        }
+
+        if name.starts_with('$') {
+            let value = self.calculator.history_entry(&name).ok_or(CalculatorError::UnboundVariable { name })?;
+            self.output.push_back(RPNToken::Number(value));
+            self.previous = Previous::Number;
+            return Ok(());
+        }
+
+        let is_call = self.peek_lexeme() == Some(&Lexeme::Open('('));
+        let value = if self.keep_variables {
+            self.calculator.resolve_constant(&name)
+        } else {
+            self.calculator.resolve(&name)
+        };
+        if !is_call && let Some(value) = value {
+            return self.push_number(value);
+        }
+        if !is_call && self.keep_variables {
+            self.output.push_back(RPNToken::Variable(name));
+            self.previous = Previous::Number;
+            return Ok(());
+        }
+
+        if !is_call || !FUNCTIONS.contains(&name.as_str()) {
+            return Err(CalculatorError::UnsupportedToken { position: i, token: name });
+        }
+        self.operations.push(RPNToken::Function(name, 0));
+        self.previous = Previous::Operator;
+        Ok(())
+    }
+
+    fn operator(&mut self, i: usize, op: &str) -> Result<(), CalculatorError> {
+        let closes_abs = op.starts_with('|')
+            && self.previous != Previous::Operator
+            && matches!(self.open_parens.last(), Some((_, '|', _)));
+
+        if self.previous == Previous::Operator {
+            match op {
+                "+" => return Ok(()),
+                // A literal that fails to read, as in `-1e400`, reports its own error.
+                "-" if self.peek_lexeme().is_none() && matches!(self.pending, Some(Err(_))) => {
+                    return self.next_lexeme().transpose().map(|_| ());
+                }
+                // A sign before a literal is read as part of it, unless an exponent or factorial
+                // follows, since those bind tighter and `-2^2` is `-(2^2)`.
+                "-" if matches!(self.peek_lexeme(), Some(Lexeme::Number(_))) => {
+                    let Some(Ok((_, Lexeme::Number(number)))) = self.next_lexeme() else {
+                        return Ok(());
+                    };
+                    if matches!(self.peek_lexeme(), Some(Lexeme::Operator(next)) if next == "^" || next == "!") {
+                        self.operations.push(RPNToken::Operation('−'));
+                        return self.push_number(number);
+                    }
+                    return self.push_number(-number);
+                }
+                "-" if matches!(self.peek_lexeme(), Some(Lexeme::Identifier(name)) if name == "inf") => {
+                    self.next_lexeme();
+                    return self.push_number(f64::NEG_INFINITY);
+                }
+                "-" => {
+                    self.operations.push(RPNToken::Operation('−'));
+                    return Ok(());
+                }
+                "|" | "||" => {
+                    if op == "||" {
+                        self.pending = Some(Ok((i + 1, Lexeme::Operator("|".to_string()))));
+                    }
+                    self.open_parens.push((i, '|', None));
+                    self.operations.push(RPNToken::Operation('('));
+                    return Ok(());
+                }
+                "~" => {
+                    self.operations.push(RPNToken::Operation('~'));
+                    return Ok(());
+                }
+                "!" => {
+                    self.operations.push(RPNToken::Operation('¬'));
+                    return Ok(());
+                }
+                _ => {
+                    return Err(CalculatorError::UnexpectedOperator { position: i, operator: op.to_string() });
+                }
+            }
+        }
+
+        match op {
+            "|" | "||" if closes_abs => {
+                if op == "||" {
+                    self.pending = Some(Ok((i + 1, Lexeme::Operator("|".to_string()))));
+                }
+                self.close(i, '|')?;
+            }
+            "%" if self.calculator.percent_mode => {
+                self.output.push_back(RPNToken::Number(100.0));
+                self.output.push_back(RPNToken::Operation('/'));
+                self.previous = Previous::Percent;
+            }
+            "!" => {
+                self.output.push_back(RPNToken::Operation('!'));
+                self.previous = Previous::Close;
+            }
+            "~" => {
+                return Err(CalculatorError::UnsupportedToken { position: i, token: op.to_string() });
+            }
+            // `?` waits on the stack at the lowest precedence. Its `:` flushes the `then` branch and
+            // takes its place, so `c ? a : b` becomes `c a b ?:` and nested conditionals group to
+            // the right.
+            ":" => {
+                loop {
+                    match self.operations.pop() {
+                        Some(RPNToken::Operation('?')) => break,
+                        Some(RPNToken::Operation('(')) | Some(RPNToken::Function(..)) | None => {
+                            return Err(CalculatorError::InvalidExpression);
+                        }
+                        Some(top) => self.output.push_back(top),
+                    }
+                }
+                self.operations.push(RPNToken::Operation(':'));
+                self.previous = Previous::Operator;
+            }
+            _ => {
+                let op = match Calculator::operator_from_symbol(op) {
+                    Some('^') if self.calculator.caret_is_xor => '⊻',
+                    Some(op) => op,
+                    None => op.chars().next().unwrap_or_default(),
+                };
+                self.push_operator(op);
+                self.previous = Previous::Operator;
+            }
+        }
+        Ok(())
+    }
+
+    fn close(&mut self, i: usize, c: char) -> Result<(), CalculatorError> {
+        let expected = match c {
+            ')' => '(',
+            ']' => '[',
+            '}' => '{',
+            _ => '|',
+        };
+        let Some((_, open, call)) = self.open_parens.pop() else {
+            return Err(CalculatorError::MismatchedParantheses { position: i });
+        };
+        if open != expected {
+            return Err(CalculatorError::MismatchedParantheses { position: i });
+        }
+        // A group with no operator of its own, as in `((2 + 3))`, passes on the one inside it.
+        let mut inner = self.bracketed.take().filter(|_| self.previous == Previous::Close).map(|(inner, _)| inner);
+        while let Some(top) = self.operations.pop() {
+            match top {
+                RPNToken::Operation('(') => break,
+                RPNToken::Operation('?') => return Err(CalculatorError::InvalidExpression),
+                RPNToken::Operation(op) if Calculator::is_unary(op) || op == ':' => self.output.push_back(top),
+                RPNToken::Operation(op) => {
+                    let looser = |inner: char| {
+                        self.calculator.operator_precedence(op) < self.calculator.operator_precedence(inner)
+                    };
+                    if inner.is_none_or(looser) {
+                        inner = Some(op);
+                    }
+                    self.output.push_back(top);
+                }
+                _ => self.output.push_back(top),
+            }
+        }
+        if call.is_none() && open != '|' {
+            let mut floor = 0;
+            if let (Some(inner), Some(&RPNToken::Operation(outer))) = (inner, self.operations.last())
+                && outer != '('
+            {
+                self.explain_brackets(inner, outer);
+                floor = self.calculator.operator_precedence(outer);
+            }
+            self.bracketed = inner.map(|inner| (inner, floor));
+        }
+        if let Some(commas) = call {
+            if self.previous == Previous::Operator && commas > 0 {
+                return Err(CalculatorError::InvalidExpression);
+            }
+            let args = if self.previous == Previous::Operator { 0 } else { commas + 1 };
+            if let Some(RPNToken::Function(name, _)) = self.operations.pop() {
+                self.output.push_back(RPNToken::Function(name, args));
+            }
+        }
+        if open == '|' {
+            self.output.push_back(RPNToken::Function("abs".to_string(), 1));
+        }
+        if let Some(last) = (self.emitted + self.output.len()).checked_sub(1) {
+            self.groups.push(last);
+        }
+        self.previous = Previous::Close;
+        Ok(())
+    }
+}
+
+impl<T: Number> Iterator for TokenStream<'_, T> {
+    type Item = Result<RPNToken, CalculatorError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.failed.take() {
+            self.done = true;
+            return Some(Err(e));
+        }
+        loop {
+            if let Some(token) = self.output.pop_front() {
+                self.emitted += 1;
+                return Some(Ok(token));
+            }
+            if self.done {
+                return None;
+            }
+            if let Err(e) = self.step() {
+                self.done = true;
+                self.output.clear();
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+pub fn evaluate(expr: &str) -> Result<f64, CalculatorError> {
+    evaluate_typed(expr)
+}
+
+/// Evaluates in the given number type, as in `evaluate_typed::<f32>("0.1 + 0.2")`.
+pub fn evaluate_typed<T: Number>(expr: &str) -> Result<T, CalculatorError> {
+    Calculator::<T>::new_typed(expr.to_string()).eval()
+}
+
+pub fn supported_functions() -> Vec<&'static str> {
+    FUNCTIONS.to_vec()
+}
+
+/// Operators as they are written, so two-character operators such as `<<` are listed whole.
+/// Operators added with `register_operator` are not included.
+pub fn supported_operators() -> Vec<&'static str> {
+    let mut symbols: Vec<&'static str> = Vec::new();
+    for &(symbol, ..) in &OPERATOR_TABLE {
+        if !symbols.contains(&symbol) {
+            symbols.push(symbol);
+        }
+    }
+    symbols
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exponent_is_right_associative() {
+        assert_eq!(evaluate("2 ^ 10"), Ok(1024.0));
+        assert_eq!(evaluate("2 ^ 3 ^ 2"), Ok(512.0));
+        assert_eq!(evaluate("(2 ^ 3) ^ 2"), Ok(64.0));
+    }
+
+    #[test]
+    fn exponent_binds_tighter_than_addition() {
+        assert_eq!(evaluate("2 + 3 ^ 2"), Ok(11.0));
+        assert_eq!(evaluate("2 * 3 ^ 2"), Ok(18.0));
+    }
+
+    #[test]
+    fn modulo_gives_the_remainder() {
+        assert_eq!(evaluate("10 % 3"), Ok(1.0));
+        assert_eq!(evaluate("10.5 % 2"), Ok(0.5));
+    }
+
+    #[test]
+    fn modulo_by_zero_is_a_zero_division() {
+        assert_eq!(evaluate("5 % 0"), Err(CalculatorError::ZeroDivision { dividend: 5.0 }));
+    }
+
+    #[test]
+    fn evaluate_is_a_one_liner() {
+        assert_eq!(evaluate("2 + 2"), Ok(4.0));
+        assert_eq!(evaluate("2 +"), Err(CalculatorError::InvalidExpression));
+    }
+
+    #[test]
+    fn functions_apply_to_their_argument() {
+        assert_eq!(evaluate("sqrt(9)"), Ok(3.0));
+        assert_eq!(evaluate("abs(-3)"), Ok(3.0));
+        assert_eq!(evaluate("sin(0)"), Ok(0.0));
+        assert_eq!(evaluate("cos(0)"), Ok(1.0));
+        assert_eq!(evaluate("ln(1)"), Ok(0.0));
+    }
+
+    #[test]
+    fn functions_nest() {
+        assert_eq!(evaluate("sqrt(sqrt(16))"), Ok(2.0));
+    }
+
+    #[test]
+    fn unknown_functions_are_unsupported() {
+        assert_eq!(
+            evaluate("foo(2)"),
+            Err(CalculatorError::UnsupportedToken { position: 0, token: "foo".to_string() })
+        );
+    }
+
+    #[test]
+    fn pi_and_e_are_constants() {
+        assert_eq!(format!("{:.5}", evaluate("pi").unwrap()), "3.14159");
+        assert_eq!(format!("{:.5}", evaluate("2 * pi").unwrap()), "6.28319");
+        assert_eq!(format!("{:.5}", evaluate("e ^ 2").unwrap()), "7.38906");
+    }
+
+    #[test]
+    fn constants_do_not_swallow_following_digits() {
+        assert_eq!(
+            evaluate("pi2"),
+            Err(CalculatorError::UnsupportedToken { position: 0, token: "pi2".to_string() })
+        );
+    }
+
+    #[test]
+    fn large_products_keep_full_precision() {
+        assert_eq!(evaluate("1000000 * 1000000 + 1"), Ok(1_000_000_000_001.0));
+        let narrow = Calculator::<f32>::new_typed("1000000 * 1000000 + 1".to_string()).eval();
+        assert_eq!(narrow, Ok(1_000_000_000_000f32));
+    }
+
+    #[test]
+    fn errors_report_their_position() {
+        let error = evaluate("2 + @").unwrap_err();
+        assert_eq!(error, CalculatorError::UnsupportedToken { position: 4, token: "@".to_string() });
+        assert_eq!(error.to_string(), "Error at position 4: The expression contains an unsupported token '@'.");
+    }
+
+    #[test]
+    fn unmatched_parentheses_report_their_position() {
+        assert_eq!(evaluate("(2 + 3"), Err(CalculatorError::MismatchedParantheses { position: 0 }));
+        assert_eq!(evaluate("2 + 3)"), Err(CalculatorError::MismatchedParantheses { position: 5 }));
+    }
+
+    #[test]
+    fn eval_all_evaluates_each_segment() {
+        let mut calculator = Calculator::new("1+1; 2*3; 4-2".to_string());
+        assert_eq!(calculator.eval_all(), vec![Ok(2.0), Ok(6.0), Ok(2.0)]);
+
+        let mut calculator = Calculator::new("1 + 1; # a;b\n 2".to_string());
+        assert_eq!(calculator.eval_all(), vec![Ok(2.0), Ok(2.0)]);
+    }
+
+    #[test]
+    fn eval_all_continues_past_a_failed_segment() {
+        let mut calculator = Calculator::new("1+1; 2*; 4-2".to_string());
+        assert_eq!(calculator.eval_all(), vec![Ok(2.0), Err(CalculatorError::InvalidExpression), Ok(2.0)]);
+    }
+
+    #[test]
+    fn adjacent_operands_multiply() {
+        assert_eq!(evaluate("2(3)"), Ok(6.0));
+        assert_eq!(evaluate("(1+1)(2+2)"), Ok(8.0));
+        assert_eq!(evaluate("2pi"), evaluate("2 * pi"));
+    }
+
+    #[test]
+    fn adjacent_numbers_do_not_multiply() {
+        assert_eq!(evaluate("2 3"), Err(CalculatorError::InvalidExpression));
+    }
+
+    #[test]
+    fn scientific_notation_is_one_literal() {
+        assert_eq!(evaluate("1e3"), Ok(1000.0));
+        assert_eq!(evaluate("1.5e2"), Ok(150.0));
+        assert_eq!(evaluate("2e-3"), Ok(0.002));
+    }
+
+    #[test]
+    fn a_bare_exponent_marker_is_the_constant_e() {
+        assert_eq!(evaluate("1e"), Ok(std::f64::consts::E));
+    }
+
+    #[test]
+    fn factorial_is_postfix() {
+        assert_eq!(evaluate("5!"), Ok(120.0));
+        assert_eq!(evaluate("0!"), Ok(1.0));
+        assert_eq!(evaluate("3! + 1"), Ok(7.0));
+        assert_eq!(evaluate("(2+1)!"), Ok(6.0));
+    }
+
+    #[test]
+    fn factorial_needs_a_non_negative_integer() {
+        assert_eq!(evaluate("(-3)!"), Err(CalculatorError::InvalidExpression));
+        assert_eq!(evaluate("2.5!"), Err(CalculatorError::InvalidExpression));
+    }
+
+    #[test]
+    fn percent_mode_divides_by_100() {
+        let percent = |expr: &str| Calculator::new(expr.to_string()).with_percent_mode().eval();
+        assert_eq!(percent("50%"), Ok(0.5));
+        assert_eq!(percent("200 + 10%"), Ok(200.1));
+        assert_eq!(percent("200 * 50%"), Ok(100.0));
+        assert_eq!(percent("(50%)"), Ok(0.5));
+    }
+
+    #[test]
+    fn percent_mode_rejects_an_operand_after_the_percent_sign() {
+        let percent = |expr: &str| Calculator::new(expr.to_string()).with_percent_mode().eval();
+        assert_eq!(percent("10 % 3"), Err(CalculatorError::InvalidExpression));
+        assert_eq!(percent("10 % (3)"), Err(CalculatorError::InvalidExpression));
+        assert_eq!(percent("10 % pi"), Err(CalculatorError::InvalidExpression));
+    }
+
+    #[test]
+    fn eval_typed_distinguishes_integers() {
+        assert_eq!(Calculator::new("6 / 2".to_string()).eval_typed(), Ok(CalcValue::Int(3)));
+        assert_eq!(Calculator::new("7 / 2".to_string()).eval_typed(), Ok(CalcValue::Float(3.5)));
+        assert_eq!(CalcValue::Int(4).to_string(), "4");
+    }
+
+    #[test]
+    fn assigned_variables_can_be_referenced() {
+        let mut calculator = Calculator::new("x = 5".to_string());
+        assert_eq!(calculator.eval(), Ok(5.0));
+        calculator.set_expr("x * 2".to_string());
+        assert_eq!(calculator.eval(), Ok(10.0));
+    }
+
+    #[test]
+    fn variables_can_be_reassigned_from_themselves() {
+        let mut calculator = Calculator::new("x = 1".to_string());
+        calculator.eval().unwrap();
+        calculator.set_expr("x = x + 1".to_string());
+        assert_eq!(calculator.eval(), Ok(2.0));
+        assert_eq!(calculator.variable("x"), Some(2.0));
+    }
+
+    #[test]
+    fn undefined_variables_are_unsupported() {
+        assert_eq!(
+            evaluate("y * 2"),
+            Err(CalculatorError::UnsupportedToken { position: 0, token: "y".to_string() })
+        );
+    }
+
+    #[test]
+    fn ans_is_the_previous_result() {
+        let mut calculator = Calculator::new("ans".to_string());
+        assert_eq!(calculator.eval(), Ok(0.0));
+        calculator.set_expr("10".to_string());
+        calculator.eval().unwrap();
+        calculator.set_expr("ans + 5".to_string());
+        assert_eq!(calculator.eval(), Ok(15.0));
+    }
+
+    #[test]
+    fn bitwise_operators_work_on_integers() {
+        assert_eq!(evaluate("6 & 3"), Ok(2.0));
+        assert_eq!(evaluate("6 | 3"), Ok(7.0));
+        assert_eq!(evaluate("1 << 4"), Ok(16.0));
+        assert_eq!(evaluate("1 << 62"), Ok(4611686018427387904.0));
+        assert_eq!(evaluate("-1 << 63"), Ok(i64::MIN as f64));
+        assert_eq!(evaluate("1 << 63"), Err(CalculatorError::Overflow));
+        assert_eq!(evaluate("3 << 62"), Err(CalculatorError::Overflow));
+        assert_eq!(evaluate("1 << 64"), Err(CalculatorError::Overflow));
+        assert_eq!(evaluate("0 << 64"), Ok(0.0));
+        assert_eq!(evaluate("12 >> 2"), Ok(3.0));
+        assert_eq!(evaluate("~5"), Ok(-6.0));
+    }
+
+    #[test]
+    fn bitwise_operators_reject_fractions() {
+        assert_eq!(evaluate("5 & 2.5"), Err(CalculatorError::InvalidExpression));
+    }
+
+    #[test]
+    fn shifts_bind_tighter_than_and() {
+        assert_eq!(evaluate("1 << 2 & 4"), Ok(4.0));
+    }
+
+    #[test]
+    fn degree_mode_converts_trig_arguments() {
+        let degrees = |expr: &str| Calculator::new(expr.to_string()).with_angle_mode(AngleMode::Degrees).eval();
+        assert!((degrees("sin(30)").unwrap() - 0.5).abs() < 1e-12);
+        assert!((degrees("sin(90)").unwrap() - 1.0).abs() < 1e-12);
+        assert_eq!(degrees("cos(0)"), Ok(1.0));
+        assert_eq!(evaluate("cos(0)"), Ok(1.0));
+    }
+
+    #[test]
+    fn to_rpn_prints_postfix_order() {
+        assert_eq!(Calculator::new("2 + 3 * 4".to_string()).to_rpn(), Ok("2 3 4 * +".to_string()));
+        assert_eq!(Calculator::new("(1 + 2) * 3".to_string()).to_rpn(), Ok("1 2 + 3 *".to_string()));
+        assert_eq!(Calculator::new("1.5 * 2".to_string()).to_rpn(), Ok("1.5 2 *".to_string()));
+    }
+
+    #[test]
+    fn unary_plus_is_ignored() {
+        assert_eq!(evaluate("+5"), Ok(5.0));
+        assert_eq!(evaluate("++5"), Ok(5.0));
+        assert_eq!(evaluate("+(-3)"), Ok(-3.0));
+        assert_eq!(evaluate("2 + +3"), Ok(5.0));
+    }
+
+    #[test]
+    fn brackets_and_braces_group() {
+        assert_eq!(evaluate("2 * [3 + (4 - 1)]"), Ok(12.0));
+        assert_eq!(evaluate("{1 + [2 * 3]} - 1"), Ok(6.0));
+    }
+
+    #[test]
+    fn bracket_types_must_match() {
+        assert_eq!(evaluate("(1 + 2]"), Err(CalculatorError::MismatchedParantheses { position: 6 }));
+        assert_eq!(evaluate("[1 + 2)"), Err(CalculatorError::MismatchedParantheses { position: 6 }));
+    }
+
+    #[test]
+    fn comparisons_give_zero_or_one() {
+        assert_eq!(evaluate("2 < 3"), Ok(1.0));
+        assert_eq!(evaluate("2 == 3"), Ok(0.0));
+        assert_eq!(evaluate("3 > 5"), Ok(0.0));
+        assert_eq!(evaluate("2 <= 2"), Ok(1.0));
+        assert_eq!(evaluate("2 >= 3"), Ok(0.0));
+        assert_eq!(evaluate("2 != 3"), Ok(1.0));
+    }
+
+    #[test]
+    fn comparisons_bind_looser_than_arithmetic() {
+        assert_eq!(evaluate("1 + 1 == 2"), Ok(1.0));
+    }
+
+    #[test]
+    fn min_and_max_take_two_arguments() {
+        assert_eq!(evaluate("max(3, 7)"), Ok(7.0));
+        assert_eq!(evaluate("min(-1, 4)"), Ok(-1.0));
+        assert_eq!(evaluate("min(max(1,2), 3)"), Ok(2.0));
+    }
+
+    #[test]
+    fn min_and_max_reject_other_argument_counts() {
+        assert_eq!(evaluate("max(5)"), Err(CalculatorError::InvalidExpression));
+        assert_eq!(evaluate("min(1, 2, 3)"), Err(CalculatorError::InvalidExpression));
+    }
+
+    #[test]
+    fn set_expr_reuses_a_calculator() {
+        let mut calculator = Calculator::new("1 + 2".to_string());
+        assert_eq!(calculator.eval(), Ok(3.0));
+        calculator.set_expr("4 * 5".to_string());
+        assert_eq!(calculator.eval(), Ok(20.0));
+    }
+
+    #[test]
+    fn errors_convert_into_boxed_errors() {
+        fn run(expr: &str) -> Result<f64, Box<dyn std::error::Error>> {
+            Ok(Calculator::new(expr.to_string()).eval()?)
+        }
+        assert_eq!(run("2 + 2").unwrap(), 4.0);
+        assert_eq!(run("2 +").unwrap_err().to_string(), CalculatorError::InvalidExpression.to_string());
+    }
+
+    #[test]
+    fn zero_division_reports_the_dividend() {
+        let error = evaluate("10 / (5 - 5)").unwrap_err();
+        assert_eq!(error, CalculatorError::ZeroDivision { dividend: 10.0 });
+        assert_eq!(error.to_string(), "Error: cannot divide 10 by 0.");
+    }
+
+    #[test]
+    fn overflowing_results_are_errors() {
+        assert_eq!(evaluate("1e308 * 10"), Err(CalculatorError::Overflow));
+        assert_eq!(Calculator::<f32>::new_typed("1e30 * 1e10".to_string()).eval(), Err(CalculatorError::Overflow));
+    }
+
+    #[test]
+    fn overflowing_literals_are_errors() {
+        assert_eq!(evaluate("1e400"), Err(CalculatorError::Overflow));
+        assert_eq!(evaluate("-1e400"), Err(CalculatorError::Overflow));
+        assert_eq!(Calculator::from_postfix("1e400 1 +".to_string()).eval(), Err(CalculatorError::Overflow));
+    }
+
+    #[test]
+    fn underscores_group_digits() {
+        assert_eq!(evaluate("1_000 + 1"), Ok(1001.0));
+        assert_eq!(evaluate("1_000_000"), Ok(1_000_000.0));
+    }
+
+    #[test]
+    fn misplaced_underscores_are_invalid() {
+        assert_eq!(evaluate("1__0"), Err(CalculatorError::InvalidExpression));
+        assert_eq!(evaluate("_5"), Err(CalculatorError::InvalidExpression));
+        assert_eq!(evaluate("5_"), Err(CalculatorError::InvalidExpression));
+    }
+
+    #[test]
+    fn comma_grouping_is_opt_in() {
+        assert_eq!(Calculator::new("1,000,000 + 1".to_string()).with_comma_grouping().eval(), Ok(1_000_001.0));
+        assert_eq!(Calculator::new("max(1, 2)".to_string()).with_comma_grouping().eval(), Ok(2.0));
+        for expr in ["1,00", "12,345,6", "1,0000", "max(1,2)"] {
+            let grouped = Calculator::new(expr.to_string()).with_comma_grouping().eval();
+            assert_eq!(grouped, Err(CalculatorError::InvalidExpression), "{}", expr);
+        }
+        assert_eq!(Calculator::new("1,234.5".to_string()).with_comma_grouping().eval(), Ok(1234.5));
+    }
+
+    #[test]
+    fn log_takes_an_explicit_base() {
+        assert_eq!(evaluate("log(2, 8)"), Ok(3.0));
+        assert_eq!(evaluate("log(10, 1000)"), Ok(3.0));
+        assert_eq!(evaluate("ln(1)"), Ok(0.0));
+    }
+
+    #[test]
+    fn log_rejects_a_base_of_one() {
+        assert_eq!(evaluate("log(1, 5)"), Err(CalculatorError::InvalidExpression));
+    }
+
+    #[test]
+    fn builder_configures_a_calculator() {
+        let mut calculator = Calculator::builder()
+            .expr("50%")
+            .percent_mode(true)
+            .angle_mode(AngleMode::Degrees)
+            .build();
+        assert_eq!(calculator.eval(), Ok(0.5));
+        calculator.set_expr("sin(90)".to_string());
+        assert_eq!(calculator.eval(), Ok(1.0));
+    }
+
+    #[test]
+    fn precision_rounds_the_result() {
+        assert_eq!(Calculator::new("0.1 + 0.2".to_string()).with_precision(2).eval(), Ok(0.3));
+        assert_eq!(evaluate("0.1 + 0.2"), Ok(0.30000000000000004));
+    }
+
+    #[test]
+    fn radix_literals_are_integers() {
+        assert_eq!(evaluate("0xff + 1"), Ok(256.0));
+        assert_eq!(evaluate("0b1010"), Ok(10.0));
+        assert_eq!(evaluate("0o17"), Ok(15.0));
+    }
+
+    #[test]
+    fn radix_literals_reject_digits_outside_their_base() {
+        assert_eq!(evaluate("0xG"), Err(CalculatorError::InvalidExpression));
+        assert_eq!(evaluate("0b2"), Err(CalculatorError::InvalidExpression));
+    }
+
+    #[test]
+    fn eval_verbose_traces_each_step() {
+        let (value, trace) = Calculator::new("2 + 3 * 4".to_string()).eval_verbose().unwrap();
+        assert_eq!(value, 14.0);
+        assert_eq!(trace, vec!["3 4 * = 12", "2 12 + = 14"]);
+    }
+
+    #[test]
+    fn parse_number_needs_no_calculator() {
+        let format = Calculator::new(String::new()).number_format();
+        let chars: Vec<char> = "12.5+3".chars().collect();
+        let mut i = 0;
+        assert_eq!(Calculator::parse_number(&chars, &mut i, false, format), Ok(12.5));
+        assert_eq!(i, 4);
+        let mut i = 0;
+        assert_eq!(Calculator::parse_number(&chars, &mut i, true, format), Ok(-12.5));
+    }
+
+    #[test]
+    fn try_new_validates_eagerly() {
+        assert_eq!(Calculator::try_new("2 +".to_string()).err(), Some(CalculatorError::InvalidExpression));
+        assert_eq!(Calculator::try_new("2 + 2".to_string()).unwrap().eval(), Ok(4.0));
+    }
+
+    #[test]
+    fn blank_expressions_are_empty() {
+        assert_eq!(evaluate(""), Err(CalculatorError::EmptyExpression));
+        assert_eq!(evaluate("  \t "), Err(CalculatorError::EmptyExpression));
+        assert_eq!(CalculatorError::EmptyExpression.to_string(), "Error: The expression is empty.");
+    }
+
+    #[test]
+    fn defined_constants_are_resolved() {
+        let mut calculator = Calculator::new("g * 2".to_string());
+        calculator.define_constant("g", 9.81);
+        assert_eq!(calculator.eval(), Ok(19.62));
+    }
+
+    #[test]
+    fn defined_constants_do_not_shadow_built_ins() {
+        let mut calculator = Calculator::new("pi".to_string());
+        calculator.define_constant("pi", 3.0);
+        assert_eq!(calculator.eval(), Ok(std::f64::consts::PI));
+    }
+
+    #[test]
+    fn floor_division_rounds_down() {
+        assert_eq!(evaluate("7 // 2"), Ok(3.0));
+        assert_eq!(evaluate("-7 // 2"), Ok(-4.0));
+        assert_eq!(evaluate("7 / 2"), Ok(3.5));
+    }
+
+    #[test]
+    fn floor_division_by_zero_is_a_zero_division() {
+        assert_eq!(evaluate("5 // 0"), Err(CalculatorError::ZeroDivision { dividend: 5.0 }));
+    }
+
+    #[test]
+    fn exit_codes_follow_the_error_kind() {
+        assert_eq!(CalculatorError::InvalidExpression.exit_code(), 2);
+        assert_eq!(CalculatorError::MismatchedParantheses { position: 0 }.exit_code(), 2);
+        assert_eq!(CalculatorError::DecimalsDisabled.exit_code(), 2);
+        assert_eq!(CalculatorError::ExpressionTooLong { max_len: 10 }.exit_code(), 2);
+        assert_eq!(CalculatorError::ZeroDivision { dividend: 1.0 }.exit_code(), 3);
+        assert_eq!(CalculatorError::Overflow.exit_code(), 4);
+        assert_eq!(CalculatorError::MathDomain.exit_code(), 5);
+    }
+
+    #[test]
+    fn tau_and_phi_are_constants() {
+        assert_eq!(evaluate("tau"), Ok(std::f64::consts::TAU));
+        assert_eq!(format!("{:.5}", evaluate("tau").unwrap()), "6.28319");
+        assert_eq!(format!("{:.5}", evaluate("phi").unwrap()), "1.61803");
+    }
+
+    #[test]
+    fn from_postfix_evaluates_rpn() {
+        assert_eq!(Calculator::from_postfix("3 4 +".to_string()).eval(), Ok(7.0));
+        assert_eq!(Calculator::from_postfix("2 3 4 * +".to_string()).eval(), Ok(14.0));
+
+        let mut calculator = Calculator::new("3 4 +".to_string());
+        assert_eq!(calculator.eval_postfix(), Ok(7.0));
+        calculator.set_expr("3 + 4".to_string());
+        assert_eq!(calculator.eval(), Ok(7.0));
+    }
+
+    #[test]
+    fn variadic_calls_keep_their_count_in_rpn() {
+        let rpn = Calculator::new("4 + sum(1, 2, 3)".to_string()).to_rpn().unwrap();
+        assert_eq!(rpn, "4 1 2 3 sum/3 +");
+        assert_eq!(Calculator::from_postfix(rpn).eval(), Ok(10.0));
+        assert_eq!(Calculator::from_postfix("1 2 3 sum".to_string()).eval(), Ok(6.0));
+        assert_eq!(Calculator::from_postfix("1 sum/2".to_string()).eval(), Err(CalculatorError::InvalidExpression));
+    }
+
+    #[test]
+    fn from_postfix_rejects_missing_operands() {
+        assert_eq!(Calculator::from_postfix("3 +".to_string()).eval(), Err(CalculatorError::InvalidExpression));
+    }
+
+    #[test]
+    fn debug_shows_the_expression() {
+        let debug = format!("{:?}", Calculator::new("1 + 2".to_string()));
+        assert!(debug.contains("1 + 2"));
+        assert!(!debug.contains("tokens"));
+        let debug = format!("{:?}", Calculator::try_new("1 + 2".to_string()).unwrap());
+        assert!(debug.contains("tokens: 3"));
+    }
+
+    #[test]
+    fn domain_violations_are_math_domain_errors() {
+        assert_eq!(evaluate("sqrt(-4)"), Err(CalculatorError::MathDomain));
+        assert_eq!(evaluate("ln(-1)"), Err(CalculatorError::MathDomain));
+        assert_eq!(evaluate("log(2, -8)"), Err(CalculatorError::MathDomain));
+        assert_eq!(evaluate("log(2, 0)"), Err(CalculatorError::MathDomain));
+        assert_eq!(evaluate("log(-2, 8)"), Err(CalculatorError::MathDomain));
+    }
+
+    #[test]
+    fn comments_run_to_the_end_of_the_line() {
+        assert_eq!(evaluate("2 + 2 # note"), Ok(4.0));
+        assert_eq!(evaluate("2 + # note\n2"), Ok(4.0));
+    }
+
+    #[test]
+    fn comment_only_lines_are_empty() {
+        assert_eq!(evaluate("# just a note"), Err(CalculatorError::EmptyExpression));
+    }
+
+    // A small xorshift generator, so the inputs are the same on every run.
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    #[test]
+    fn arbitrary_input_never_panics() {
+        const PIECES: [&str; 48] = [
+            "0", "1", "2", "9", ".", ",", "_", "e", "E", "x", "0x", "0b", "1e400", "+", "-", "*", "/", "//", "%",
+            "^", "!", "~", "&", "|", "<<", ">>", "<", "<=", "==", "!=", "&&", "||", "?", ":", "(", ")", "[", "]",
+            "{", "}", " ", "=", "#", "$1", "pi", "sqrt", "sum", "٣",
+        ];
+        let mut rng = XorShift(0x9E37_79B9_7F4A_7C15);
+
+        for n in 0..5000 {
+            let len = rng.below(16);
+            let expr: String = (0..len).map(|_| PIECES[rng.below(PIECES.len())]).collect();
+            let outcome = std::panic::catch_unwind(|| {
+                let mut calculator = Calculator::new(expr.clone());
+                calculator = match n % 4 {
+                    0 => calculator,
+                    1 => calculator.with_percent_mode().with_caret_xor(),
+                    2 => calculator.with_integer_mode().with_sexagesimal(),
+                    _ => calculator.with_decimal_separator(','),
+                };
+                let _ = calculator.validate();
+                let _ = calculator.explain();
+                let _ = calculator.normalized_expr();
+                let _ = calculator.parse_ast().map(|ast| ast.eval());
+                let _ = calculator.eval();
+                let _ = calculator.eval_all();
+                let _ = calculator.eval_postfix();
+            });
+            assert!(outcome.is_ok(), "panicked on {:?}", expr);
+        }
+    }
+
+    #[test]
+    fn each_error_has_a_stable_kind() {
+        let kinds = [
+            (CalculatorError::UnsupportedToken { position: 0, token: "@".to_string() }, "unsupported_token"),
+            (CalculatorError::MismatchedParantheses { position: 0 }, "mismatched_parens"),
+            (CalculatorError::UnexpectedOperator { position: 0, operator: "*".to_string() }, "unexpected_operator"),
+            (CalculatorError::UnboundVariable { name: "x".to_string() }, "unbound_variable"),
+            (CalculatorError::InvalidExpression, "invalid_expression"),
+            (CalculatorError::InvalidDecimal, "invalid_decimal"),
+            (CalculatorError::DecimalsDisabled, "decimals_disabled"),
+            (CalculatorError::ExpressionTooLong { max_len: 10 }, "expression_too_long"),
+            (CalculatorError::ZeroDivision { dividend: 1.0 }, "zero_division"),
+            (CalculatorError::Overflow, "overflow"),
+            (CalculatorError::MathDomain, "math_domain"),
+            (CalculatorError::EmptyExpression, "empty_expression"),
+        ];
+        for (error, kind) in kinds {
+            assert_eq!(error.kind(), kind);
+        }
+    }
+
+    #[test]
+    fn unicode_digits_are_normalized() {
+        assert_eq!(evaluate("２＋２"), Ok(4.0));
+        assert_eq!(evaluate("٢+٢"), Ok(4.0));
+        assert_eq!(evaluate("१० * ३"), Ok(30.0));
+    }
+
+    #[test]
+    fn pipes_take_the_absolute_value() {
+        assert_eq!(evaluate("|-5|"), Ok(5.0));
+        assert_eq!(evaluate("|3 - 7|"), Ok(4.0));
+    }
+
+    #[test]
+    fn unmatched_pipes_are_mismatched() {
+        assert_eq!(evaluate("|3"), Err(CalculatorError::MismatchedParantheses { position: 0 }));
+    }
+
+    #[test]
+    fn tokens_are_public() {
+        let calculator = Calculator::try_new("1 + 2".to_string()).unwrap();
+        let tokens: Vec<Token> = calculator.tokens().collect();
+        assert_eq!(tokens, vec![Token::Number(1.0), Token::Number(2.0), Token::Operator("+".to_string())]);
+
+        let calculator = Calculator::try_new("1 << 2 <= 3 == 1".to_string()).unwrap();
+        let operators: Vec<String> = calculator
+            .tokens()
+            .filter_map(|token| match token {
+                Token::Operator(symbol) => Some(symbol),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(operators, ["<<", "<=", "=="]);
+    }
+
+    #[test]
+    fn memory_adds_recalls_and_clears() {
+        let mut calculator = Calculator::new("5 + 5".to_string());
+        calculator.eval().unwrap();
+        calculator.memory_add();
+        assert_eq!(calculator.memory(), 10.0);
+        calculator.set_expr("MR * 2".to_string());
+        assert_eq!(calculator.eval(), Ok(20.0));
+        calculator.memory_clear();
+        assert_eq!(calculator.memory(), 0.0);
+    }
+
+    #[test]
+    fn comma_can_be_the_decimal_separator() {
+        let comma = |expr: &str| Calculator::new(expr.to_string()).with_decimal_separator(',').eval();
+        assert!((comma("3,14 + 1").unwrap() - 4.14).abs() < 1e-12);
+        assert_eq!(comma("max(1,5, 2)"), Ok(2.0));
+    }
+
+    #[test]
+    fn consecutive_operators_name_the_offender() {
+        let unexpected = |position, operator: &str| CalculatorError::UnexpectedOperator { position, operator: operator.to_string() };
+        assert_eq!(evaluate("2 + * 3"), Err(unexpected(4, "*")));
+        assert_eq!(evaluate("5 */ 2"), Err(unexpected(3, "/")));
+        assert_eq!(evaluate("2 ** 3"), Err(unexpected(3, "*")));
+        assert_eq!(evaluate("2 * -3"), Ok(-6.0));
+    }
+
+    #[test]
+    fn parse_once_and_evaluate_with_bindings() {
+        let mut calculator = Calculator::new("x * 2".to_string());
+        calculator.parse().unwrap();
+        let bind = |x: f64| HashMap::from([("x".to_string(), x)]);
+        assert_eq!(calculator.eval_with(&bind(3.0)), Ok(6.0));
+        assert_eq!(calculator.eval_with(&bind(10.0)), Ok(20.0));
+        assert_eq!(
+            calculator.eval_with(&HashMap::new()),
+            Err(CalculatorError::UnboundVariable { name: "x".to_string() })
+        );
+    }
+
+    #[test]
+    fn constant_expressions_fold_to_one_token() {
+        let mut calculator = Calculator::new("2 * 3 + 4".to_string());
+        calculator.parse().unwrap();
+        assert!(calculator.is_constant());
+        assert_eq!(calculator.tokens().collect::<Vec<_>>(), vec![Token::Number(10.0)]);
+
+        assert!(Calculator::new("2 * pi".to_string()).is_constant());
+        assert!(!Calculator::new("2 * x".to_string()).is_constant());
+        assert!(!Calculator::new("2 *".to_string()).is_constant());
+        assert!(!Calculator::new(String::new()).is_constant());
+    }
+
+    #[test]
+    fn folding_keeps_variables() {
+        let mut calculator = Calculator::new("x * (2 + 3)".to_string());
+        calculator.parse().unwrap();
+        assert!(!calculator.is_constant());
+        assert_eq!(calculator.tokens().count(), 3);
+    }
+
+    #[test]
+    fn rounding_functions() {
+        assert_eq!(evaluate("floor(3.7)"), Ok(3.0));
+        assert_eq!(evaluate("ceil(3.2)"), Ok(4.0));
+        assert_eq!(evaluate("round(2.5)"), Ok(3.0));
+        assert_eq!(evaluate("trunc(-3.7)"), Ok(-3.0));
+    }
+
+    #[test]
+    fn registered_operators() {
+        let mut calculator = Calculator::new("(3 @ 4) + 1 @ 0".to_string());
+        calculator.register_operator('@', 10, f64::hypot).unwrap();
+        assert_eq!(calculator.eval(), Ok(6.0));
+
+        assert_eq!(calculator.register_operator('+', 12, |a, b| a - b), Err(CalculatorError::InvalidExpression));
+        assert_eq!(calculator.register_operator('(', 10, f64::hypot), Err(CalculatorError::InvalidExpression));
+        assert_eq!(calculator.register_operator('7', 10, f64::hypot), Err(CalculatorError::InvalidExpression));
+        assert_eq!(calculator.register_operator('#', 0, f64::hypot), Err(CalculatorError::InvalidExpression));
+        assert_eq!(calculator.register_operator('@', 0, f64::max), Err(CalculatorError::InvalidExpression));
+
+        calculator.set_expr("2 + 3 * 4".to_string());
+        assert_eq!(calculator.eval(), Ok(14.0));
+    }
+
+    #[test]
+    fn leading_equals_sign_is_skipped() {
+        assert_eq!(evaluate("=2+2"), evaluate("2+2"));
+        assert_eq!(evaluate("  = 3 * 4"), Ok(12.0));
+    }
+
+    #[test]
+    fn gcd_and_lcm() {
+        assert_eq!(evaluate("gcd(12, 18)"), Ok(6.0));
+        assert_eq!(evaluate("lcm(4, 6)"), Ok(12.0));
+        assert_eq!(evaluate("gcd(0, 5)"), Ok(5.0));
+        assert_eq!(evaluate("gcd(2.5, 5)"), Err(CalculatorError::InvalidExpression));
+    }
+
+    #[test]
+    fn pow_function() {
+        assert_eq!(evaluate("pow(2, 10)"), Ok(1024.0));
+        assert_eq!(evaluate("pow(9, 0.5)"), Ok(3.0));
+    }
+
+    #[test]
+    fn whitespace_after_unary_minus() {
+        assert_eq!(evaluate("2 *  -3"), Ok(-6.0));
+        assert_eq!(evaluate("(\t-3)"), Ok(-3.0));
+        assert_eq!(evaluate("-  5"), Ok(-5.0));
+    }
+
+    #[test]
+    fn prefix_minus_negates_any_operand() {
+        assert_eq!(evaluate("-pi"), Ok(-std::f64::consts::PI));
+        assert_eq!(evaluate("-(3)"), Ok(-3.0));
+        assert_eq!(evaluate("-sqrt(4)"), Ok(-2.0));
+        assert_eq!(evaluate("2 * -(1)"), Ok(-2.0));
+        assert_eq!(evaluate("-2!"), Ok(-2.0));
+        assert_eq!(evaluate("-2^2"), Ok(-4.0));
+        assert_eq!(evaluate("2^-2"), Ok(0.25));
+        assert_eq!(evaluate("(-2)^2"), Ok(4.0));
+        assert_eq!(evaluate("--3"), Ok(3.0));
+        assert_eq!(evaluate("-(2 + 3) * 2"), Ok(-10.0));
+
+        let rpn = Calculator::new("-(1 + 2) - -3".to_string()).to_rpn().unwrap();
+        assert_eq!(rpn, "1 2 + neg -3 -");
+        assert_eq!(Calculator::from_postfix(rpn).eval(), Ok(0.0));
+    }
+
+    #[test]
+    fn caret_is_power_or_xor() {
+        assert_eq!(evaluate("5 ^ 3"), Ok(125.0));
+        assert_eq!(Calculator::new("5 ^ 3".to_string()).with_caret_xor().eval(), Ok(6.0));
+        assert_eq!(Calculator::new("5.5 ^ 3".to_string()).with_caret_xor().eval(), Err(CalculatorError::InvalidExpression));
+    }
+
+    #[test]
+    fn streamed_tokens_match_stored_tokens() {
+        let calculator = Calculator::try_new("1 + 2 * 3".to_string()).unwrap();
+        let streamed = calculator.stream_tokens().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(streamed, calculator.tokens().collect::<Vec<_>>());
+        assert_eq!(streamed.len(), 5);
+
+        let mut calculator = Calculator::new("1 + 2 * 3".to_string());
+        assert_eq!(calculator.eval(), Ok(7.0));
+        assert_eq!(format!("{:?}", calculator), "Calculator { expr: \"1 + 2 * 3\" }");
+    }
+
+    #[test]
+    fn explain_describes_precedence() {
+        let notes = Calculator::new("2 + 3 * 4".to_string()).explain().unwrap();
+        assert_eq!(notes, vec!["multiplication binds tighter than addition, so the multiplication is computed first."]);
+
+        let notes = Calculator::new("1 ? 2 : 3 ? 4 : 5".to_string()).explain().unwrap();
+        assert_eq!(notes.len(), 1);
+        assert!(notes[0].contains("right to left"));
+
+        let bracketed = "the brackets make the addition happen before the multiplication, which would otherwise be \
+            computed first.";
+        for expr in ["(2 + 3) * 4", "4 * (2 + 3)", "((2 + 3)) * 4", "(2 + 3)(4)"] {
+            assert_eq!(Calculator::new(expr.to_string()).explain().unwrap(), vec![bracketed], "{}", expr);
+        }
+        assert_eq!(Calculator::new("(2 * 3) + 4".to_string()).explain().unwrap(), Vec::<String>::new());
+        let notes = Calculator::new("-(2 + 3) * 4".to_string()).explain().unwrap();
+        assert_eq!(notes.iter().filter(|note| note.starts_with("the brackets")).count(), 1);
+        assert_eq!(Calculator::new("sqrt(2 + 3) * 4".to_string()).explain().unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn compound_assignment() {
+        let mut calculator = Calculator::new("x = 1".to_string());
+        assert_eq!(calculator.eval(), Ok(1.0));
+        calculator.set_expr("x += 4".to_string());
+        assert_eq!(calculator.eval(), Ok(5.0));
+        calculator.set_expr("x *= 2".to_string());
+        assert_eq!(calculator.eval(), Ok(10.0));
+        calculator.set_expr("y += 1".to_string());
+        assert_eq!(calculator.eval(), Err(CalculatorError::InvalidExpression));
+    }
+
+    #[test]
+    fn sum_and_avg_take_any_number_of_arguments() {
+        assert_eq!(evaluate("sum(1, 2, 3)"), Ok(6.0));
+        assert_eq!(evaluate("avg(2, 4, 6)"), Ok(4.0));
+        assert_eq!(evaluate("sum(5)"), Ok(5.0));
+        assert!(evaluate("sum()").is_err());
+    }
+
+    #[test]
+    fn signed_zero_divisors_and_epsilon() {
+        assert_eq!(evaluate("1 / 0.0"), Err(CalculatorError::ZeroDivision { dividend: 1.0 }));
+        assert_eq!(evaluate("1 / -0.0"), Err(CalculatorError::ZeroDivision { dividend: 1.0 }));
+        assert_eq!(evaluate("1 / 1e-12"), Ok(1e12));
+        let mut calculator = Calculator::new("1 / 1e-12".to_string()).with_zero_epsilon(1e-9);
+        assert_eq!(calculator.eval(), Err(CalculatorError::ZeroDivision { dividend: 1.0 }));
+        assert_eq!(evaluate("1 / 1e-310"), Err(CalculatorError::Overflow));
+    }
+
+    #[test]
+    fn logical_operators() {
+        assert_eq!(evaluate("1 && 0"), Ok(0.0));
+        assert_eq!(evaluate("0 || 1"), Ok(1.0));
+        assert_eq!(evaluate("!0"), Ok(1.0));
+        assert_eq!(evaluate("1 && (2 > 1)"), Ok(1.0));
+
+        let rpn = Calculator::new("!!0 + !0!".to_string()).to_rpn().unwrap();
+        assert_eq!(rpn, "0 not not 0 ! not +");
+        assert_eq!(Calculator::from_postfix(rpn).eval(), Ok(0.0));
+    }
+
+    #[test]
+    fn conditional_operator() {
+        assert_eq!(evaluate("1 ? 2 : 3"), Ok(2.0));
+        assert_eq!(evaluate("0 ? 2 : 3"), Ok(3.0));
+        assert_eq!(evaluate("0 ? 1 : 1 ? 4 : 5"), Ok(4.0));
+        assert_eq!(evaluate("2 > 1 ? 10 : 20"), Ok(10.0));
+    }
+
+    #[test]
+    fn every_entry_point_takes_a_number_type() {
+        assert_eq!(evaluate_typed::<f32>("0.1 + 0.2"), Ok(0.1f32 + 0.2f32));
+        assert_eq!(evaluate_typed::<f64>("0.1 + 0.2"), Ok(0.1 + 0.2));
+        assert_eq!(Calculator::<f32>::from_postfix_typed("0.1 0.2 +".to_string()).eval(), Ok(0.1f32 + 0.2f32));
+        assert_eq!(Calculator::<f32>::try_new_typed("1 +".to_string()).err(), Some(CalculatorError::InvalidExpression));
+
+        let mut calculator = Calculator::<f32>::builder_typed().expr("2 @ 3").precision(2).build();
+        calculator.register_operator('@', 10, |a: f32, b: f32| a / b).unwrap();
+        assert_eq!(calculator.eval(), Ok(0.67));
+    }
+
+    #[test]
+    fn integer_mode_is_exact() {
+        let int = |expr: &str| Calculator::new(expr.to_string()).with_integer_mode().eval();
+        assert_eq!(int("2 + 2"), Ok(4.0));
+        assert_eq!(int("8 / 2"), Ok(4.0));
+        assert_eq!(int("7 / 2"), Err(CalculatorError::InvalidExpression));
+        assert_eq!(int("2.5 + 1"), Err(CalculatorError::InvalidExpression));
+        assert_eq!(int("4294967296 * 4294967296"), Err(CalculatorError::Overflow));
+    }
+
+    #[test]
+    fn history_references() {
+        let mut calculator = Calculator::new(String::new());
+        for expr in ["2", "3 * 4", "10"] {
+            calculator.set_expr(expr.to_string());
+            calculator.eval().unwrap();
+        }
+        calculator.set_expr("$1 + $2".to_string());
+        assert_eq!(calculator.eval(), Ok(14.0));
+        calculator.set_expr("$4".to_string());
+        assert_eq!(calculator.eval(), Ok(14.0));
+        calculator.set_expr("$9".to_string());
+        assert_eq!(calculator.eval(), Err(CalculatorError::UnboundVariable { name: "$9".to_string() }));
+    }
+
+    #[test]
+    fn validate_reports_every_problem() {
+        let errors = Calculator::new("2 + @ + (3".to_string()).validate();
+        assert_eq!(
+            errors,
+            vec![
+                CalculatorError::UnsupportedToken { position: 4, token: "@".to_string() },
+                CalculatorError::MismatchedParantheses { position: 8 },
+            ]
+        );
+
+        let errors = Calculator::new("foo + bar)".to_string()).validate();
+        assert_eq!(errors.len(), 3);
+        assert_eq!(errors.last(), Some(&CalculatorError::MismatchedParantheses { position: 9 }));
+        assert_eq!(Calculator::new(" ".to_string()).validate(), vec![CalculatorError::EmptyExpression]);
+        assert!(Calculator::new("1 + 2".to_string()).validate().is_empty());
+    }
+
+    #[test]
+    fn line_endings_and_invisible_characters() {
+        assert_eq!(evaluate("2\r\n+\r\n2"), Ok(4.0));
+        assert_eq!(evaluate("\u{200B}2 + 2\u{FEFF}"), Ok(4.0));
+        assert_eq!(
+            evaluate("2 +\u{7} 2"),
+            Err(CalculatorError::UnsupportedToken { position: 3, token: "U+0007".to_string() })
+        );
+    }
+
+    #[test]
+    fn eval_timed_returns_the_result() {
+        let (result, elapsed) = Calculator::new("2 ^ 10".to_string()).eval_timed();
+        assert_eq!(result, Ok(1024.0));
+        assert!(elapsed < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn operations_are_counted() {
+        let mut calculator = Calculator::new("2 + 3 * 4".to_string());
+        calculator.eval().unwrap();
+        assert_eq!(calculator.operations_performed(), 2);
+        calculator.set_expr("sqrt(16) + -1".to_string());
+        calculator.eval().unwrap();
+        assert_eq!(calculator.operations_performed(), 2);
+    }
+
+    #[test]
+    fn sexagesimal_literals() {
+        let sexagesimal = |expr: &str| Calculator::new(expr.to_string()).with_sexagesimal().eval();
+        assert_eq!(sexagesimal("1:30"), Ok(1.5));
+        assert_eq!(sexagesimal("12:30:00"), Ok(12.5));
+        assert_eq!(sexagesimal("1:30 + 0:15"), Ok(1.75));
+        assert_eq!(sexagesimal("1:60"), Err(CalculatorError::InvalidExpression));
+    }
+
+    #[test]
+    fn supported_lists() {
+        let functions = supported_functions();
+        for name in ["sqrt", "sin", "log", "gcd", "sum", "sign", "pi"] {
+            assert!(functions.contains(&name), "missing {}", name);
+        }
+        let operators = supported_operators();
+        for symbol in ["+", "-", "*", "/", "//", "^", "<<", "&&", "?"] {
+            assert!(operators.contains(&symbol), "missing {}", symbol);
+        }
+        assert_eq!(operators.iter().filter(|&&symbol| symbol == "^").count(), 1);
+    }
+
+    #[test]
+    fn every_binary_operator_is_in_the_operator_table() {
+        for (op, _) in BINARY_OPERATORS {
+            assert!(Calculator::precedence(op) > 0, "no precedence for {}", op);
+            let written = Calculator::operator_symbol(op);
+            assert!(Calculator::operator_from_symbol(&written).is_some(), "{} cannot be written", op);
+        }
+    }
+
+    #[test]
+    fn division_by_zero_policies() {
+        let divide = |expr: &str, policy| Calculator::new(expr.to_string()).with_div_by_zero(policy).eval();
+        assert_eq!(divide("1 / 0", DivByZeroPolicy::Error), Err(CalculatorError::ZeroDivision { dividend: 1.0 }));
+        assert_eq!(divide("-1 / 0", DivByZeroPolicy::Error), Err(CalculatorError::ZeroDivision { dividend: -1.0 }));
+        assert_eq!(divide("1 / 0", DivByZeroPolicy::Infinity), Ok(f64::INFINITY));
+        assert_eq!(divide("-1 / 0", DivByZeroPolicy::Infinity), Ok(f64::NEG_INFINITY));
+        assert!(divide("1 / 0", DivByZeroPolicy::Nan).unwrap().is_nan());
+        assert!(divide("-1 / 0", DivByZeroPolicy::Nan).unwrap().is_nan());
+    }
+
+    #[test]
+    fn sign_function() {
+        assert_eq!(evaluate("sign(-3)"), Ok(-1.0));
+        assert_eq!(evaluate("sign(0)"), Ok(0.0));
+        assert_eq!(evaluate("sign(5)"), Ok(1.0));
+    }
+
+    #[test]
+    fn normalized_expressions() {
+        let normalized = |expr: &str| {
+            let mut calculator = Calculator::new("x = 1".to_string());
+            calculator.eval().unwrap();
+            calculator.set_expr(expr.to_string());
+            calculator.normalized_expr()
+        };
+        assert_eq!(normalized("2(3)"), "2*(3)");
+        assert_eq!(normalized("=2x"), "2*x");
+        assert_eq!(normalized("y = 2x"), "y = 2*x");
+        assert_eq!(normalized("x += 4"), "x = x + (4)");
+        assert_eq!(normalized("x *= 2x # twice"), "x = x * (2*x) # twice");
+    }
+
+    #[test]
+    fn integer_only_rejects_decimal_literals() {
+        let integer_only = |expr: &str| Calculator::new(expr.to_string()).with_integer_only().eval();
+        assert_eq!(integer_only("2.5"), Err(CalculatorError::DecimalsDisabled));
+        assert_eq!(integer_only("2.0 + 1"), Err(CalculatorError::DecimalsDisabled));
+        assert_eq!(integer_only("5"), Ok(5.0));
+        assert_eq!(integer_only("7 / 2"), Ok(3.5));
+        assert_eq!(integer_only("1e-3"), Err(CalculatorError::DecimalsDisabled));
+        assert_eq!(integer_only("1e3"), Ok(1000.0));
+    }
+
+    #[test]
+    fn lexer_takes_the_longest_operator() {
+        let lex = |expr: &str| Calculator::new(expr.to_string()).lex();
+        let operator = |op: &str| Lexeme::Operator(op.to_string());
+        assert_eq!(lex("2<=3"), Ok(vec![Lexeme::Number(2.0), operator("<="), Lexeme::Number(3.0)]));
+        assert_eq!(lex("1//2"), Ok(vec![Lexeme::Number(1.0), operator("//"), Lexeme::Number(2.0)]));
+        assert_eq!(
+            lex("max(1, x)"),
+            Ok(vec![
+                Lexeme::Identifier("max".to_string()),
+                Lexeme::Open('('),
+                Lexeme::Number(1.0),
+                Lexeme::Comma,
+                Lexeme::Identifier("x".to_string()),
+                Lexeme::Close(')'),
+            ])
+        );
+    }
+
+    #[test]
+    fn zero_argument_functions() {
+        assert_eq!(evaluate("pi()"), evaluate("pi"));
+        assert_eq!(evaluate("2 * e()"), Ok(2.0 * std::f64::consts::E));
+        assert_eq!(evaluate("sqrt()"), Err(CalculatorError::InvalidExpression));
+    }
+
+    #[test]
+    fn rounded_literals_are_reported() {
+        let mut calculator = Calculator::<f32>::new_typed("16777217".to_string());
+        assert_eq!(calculator.eval(), Ok(16777216.0));
+        assert_eq!(calculator.warnings().len(), 1);
+        assert!(calculator.warnings()[0].contains("16777217"));
+
+        let mut calculator = Calculator::<f32>::new_typed("16777216 + 0.5".to_string());
+        calculator.eval().unwrap();
+        assert!(calculator.warnings().is_empty());
+
+        let mut calculator = Calculator::new("16777217".to_string());
+        calculator.eval().unwrap();
+        assert!(calculator.warnings().is_empty());
+    }
+
+    #[test]
+    fn infinity_and_nan_literals() {
+        assert_eq!(evaluate("inf"), Ok(f64::INFINITY));
+        assert_eq!(evaluate("-inf"), Ok(f64::NEG_INFINITY));
+        assert_eq!(evaluate("inf + 1"), Ok(f64::INFINITY));
+        assert!(evaluate("nan").unwrap().is_nan());
+        assert_eq!(
+            Calculator::new("inf = 2".to_string()).eval(),
+            Err(CalculatorError::UnsupportedToken { position: 4, token: "=".to_string() })
+        );
+    }
+
+    #[test]
+    fn length_cap_rejects_long_expressions() {
+        let capped = |expr: &str| Calculator::new(expr.to_string()).with_max_len(5).eval();
+        assert_eq!(capped("1 + 100"), Err(CalculatorError::ExpressionTooLong { max_len: 5 }));
+        assert_eq!(capped("1 + 1"), Ok(2.0));
+        assert_eq!(
+            Calculator::new("1 + 100".to_string()).with_max_len(5).validate(),
+            vec![CalculatorError::ExpressionTooLong { max_len: 5 }]
+        );
+    }
 }
@@ -0,0 +1,437 @@
+use std::fmt;
+
+use crate::calculator::{ Calculator, CalculatorError, Number };
+
+/// An expression tree, as built by `Calculator::parse_ast`.
+pub enum Expr {
+    Num(f64),
+    BinOp(char, Box<Expr>, Box<Expr>),
+    UnaryOp(char, Box<Expr>),
+    Call(String, Vec<Expr>),
+    Cond(Box<Expr>, Box<Expr>, Box<Expr>),
+}
+
+enum Step<'a> {
+    Visit(&'a Expr),
+    Apply(&'a Expr),
+    Select(&'a Expr, &'a Expr),
+}
+
+impl Expr {
+    /// Evaluates as a default calculator would, so operators added with `register_operator` need
+    /// `eval_in` with the calculator they were registered on.
+    pub fn eval(&self) -> Result<f64, CalculatorError> {
+        self.eval_in(&Calculator::new(String::new()))
+    }
+
+    /// Evaluates under the configuration of `calculator`, so its angle mode, division by zero
+    /// policy, integer mode, precision and registered operators apply as they would to its own
+    /// `eval`. Walks the tree with an explicit stack so deeply nested expressions cannot overflow
+    /// the call stack.
+    pub fn eval_in<T: Number>(&self, calculator: &Calculator<T>) -> Result<T, CalculatorError> {
+        let mut steps = vec![Step::Visit(self)];
+        let mut values: Vec<f64> = Vec::new();
+
+        while let Some(step) = steps.pop() {
+            match step {
+                Step::Visit(Expr::Num(n)) => values.push(*n),
+                Step::Visit(Expr::Cond(condition, then, otherwise)) => {
+                    steps.push(Step::Select(then, otherwise));
+                    steps.push(Step::Visit(condition));
+                }
+                Step::Visit(expr) => {
+                    steps.push(Step::Apply(expr));
+                    match expr {
+                        Expr::BinOp(_, lhs, rhs) => {
+                            steps.push(Step::Visit(rhs));
+                            steps.push(Step::Visit(lhs));
+                        }
+                        Expr::UnaryOp(_, operand) => steps.push(Step::Visit(operand)),
+                        Expr::Call(_, args) => steps.extend(args.iter().rev().map(Step::Visit)),
+                        Expr::Num(_) | Expr::Cond(..) => {}
+                    }
+                }
+                // Only the selected branch is evaluated, so `x != 0 ? 1 / x : 0` is safe.
+                Step::Select(then, otherwise) => {
+                    let condition = values.pop().ok_or(CalculatorError::InvalidExpression)?;
+                    steps.push(Step::Visit(if condition != 0.0 { then } else { otherwise }));
+                }
+                Step::Apply(expr) => {
+                    let value = match expr {
+                        Expr::Num(n) => Ok(*n),
+                        Expr::Cond(..) => Err(CalculatorError::InvalidExpression),
+                        Expr::BinOp(op, ..) => {
+                            let b = values.pop().ok_or(CalculatorError::InvalidExpression)?;
+                            let a = values.pop().ok_or(CalculatorError::InvalidExpression)?;
+                            calculator.apply_binary(*op, a, b)
+                        }
+                        Expr::UnaryOp(op, _) => {
+                            let x = values.pop().ok_or(CalculatorError::InvalidExpression)?;
+                            calculator.apply_unary_op(*op, x)
+                        }
+                        Expr::Call(name, args) => {
+                            let start = values
+                                .len()
+                                .checked_sub(args.len())
+                                .ok_or(CalculatorError::InvalidExpression)?;
+                            let args = values.split_off(start);
+                            calculator.apply_call(name, &args)
+                        }
+                    };
+                    values.push(value?);
+                }
+            }
+        }
+
+        values.pop().ok_or(CalculatorError::InvalidExpression).map(|value| calculator.round(value))
+    }
+}
+
+impl Expr {
+    /// Chained comparisons read as in mathematics, so `1 < x < 3` is `1 < x && x < 3` rather than
+    /// a comparison of `1 < x` with 3. The shared operand is evaluated once per comparison. A
+    /// bracketed `lhs`, as in `(1 < x) < 3`, is compared as it is.
+    pub(crate) fn binary(op: char, lhs: Expr, rhs: Expr, grouped: bool) -> Expr {
+        let is_relational = |op: char| matches!(op, '<' | '>' | '≤' | '≥');
+        let last = match &lhs {
+            Expr::BinOp('∧', _, last) => last,
+            _ => &lhs,
+        };
+        match last {
+            Expr::BinOp(inner, _, middle) if !grouped && is_relational(op) && is_relational(*inner) => {
+                let next = Expr::BinOp(op, middle.clone(), Box::new(rhs));
+                Expr::BinOp('∧', Box::new(lhs), Box::new(next))
+            }
+            _ => Expr::BinOp(op, Box::new(lhs), Box::new(rhs)),
+        }
+    }
+}
+
+enum Part<'a> {
+    Node(&'a Expr, bool),
+    Text(String),
+}
+
+impl Expr {
+    fn binding(&self) -> u8 {
+        match self {
+            Expr::BinOp(op, ..) | Expr::UnaryOp(op @ ('−' | '~' | '¬'), _) => Calculator::precedence(*op),
+            Expr::UnaryOp(..) => u8::MAX - 1,
+            // A negative literal binds like negation, so `(-2) ^ 2` keeps its brackets.
+            Expr::Num(n) if n.is_sign_negative() && !n.is_nan() => Calculator::precedence('−'),
+            Expr::Cond(..) => 0,
+            Expr::Num(_) | Expr::Call(..) => u8::MAX,
+        }
+    }
+}
+
+// Prints infix notation with only the parentheses that precedence and associativity require,
+// so `2 + (3 * 4)` prints as `2 + 3 * 4` while `(2 + 3) * 4` keeps its parentheses.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = vec![Part::Node(self, false)];
+
+        while let Some(part) = parts.pop() {
+            let (expr, parens) = match part {
+                Part::Text(text) => {
+                    write!(f, "{}", text)?;
+                    continue;
+                }
+                Part::Node(expr, parens) => (expr, parens),
+            };
+            if parens {
+                parts.push(Part::Text(")".to_string()));
+                parts.push(Part::Node(expr, false));
+                parts.push(Part::Text("(".to_string()));
+                continue;
+            }
+
+            match expr {
+                // Written as the literal it is read back from, rather than `NaN`.
+                Expr::Num(n) if n.is_nan() => write!(f, "nan")?,
+                Expr::Num(n) => write!(f, "{}", n)?,
+                Expr::BinOp(op, lhs, rhs) => {
+                    // The precedence of an operator added with `register_operator` is not known
+                    // here, so it brackets any operand that is itself an operation, and is
+                    // bracketed in turn as the operand of one.
+                    let precedence = match Calculator::precedence(*op) {
+                        0 => u8::MAX - 2,
+                        precedence => precedence,
+                    };
+                    let right_assoc = Calculator::is_right_associative(*op);
+                    // A comparison on the left stays bracketed, or `(1 < 5) < 3` would read back as a chain.
+                    let comparison = |op: char| matches!(op, '<' | '>' | '≤' | '≥');
+                    let chained = comparison(*op) && matches!(**lhs, Expr::BinOp(inner, ..) if comparison(inner));
+                    let lhs_parens =
+                        chained || lhs.binding() < precedence || (right_assoc && lhs.binding() == precedence);
+                    let rhs_parens = !matches!(**rhs, Expr::UnaryOp('−' | '~' | '¬', _))
+                        && (rhs.binding() < precedence || (!right_assoc && rhs.binding() == precedence));
+                    parts.push(Part::Node(rhs, rhs_parens));
+                    parts.push(Part::Text(format!(" {} ", Calculator::operator_symbol(*op))));
+                    parts.push(Part::Node(lhs, lhs_parens));
+                }
+                Expr::UnaryOp(op @ ('−' | '~' | '¬'), operand) => {
+                    parts.push(Part::Node(operand, operand.binding() <= expr.binding()));
+                    parts.push(Part::Text(Calculator::operator_symbol(*op)));
+                }
+                Expr::UnaryOp(op, operand) => {
+                    parts.push(Part::Text(op.to_string()));
+                    parts.push(Part::Node(operand, operand.binding() < expr.binding()));
+                }
+                Expr::Cond(condition, then, otherwise) => {
+                    parts.push(Part::Node(otherwise, false));
+                    parts.push(Part::Text(" : ".to_string()));
+                    parts.push(Part::Node(then, false));
+                    parts.push(Part::Text(" ? ".to_string()));
+                    parts.push(Part::Node(condition, condition.binding() == 0));
+                }
+                Expr::Call(name, args) => {
+                    parts.push(Part::Text(")".to_string()));
+                    for (i, arg) in args.iter().enumerate().rev() {
+                        parts.push(Part::Node(arg, false));
+                        if i > 0 {
+                            parts.push(Part::Text(", ".to_string()));
+                        }
+                    }
+                    parts.push(Part::Text(format!("{}(", name)));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Expr {
+    fn children(&self) -> Vec<&Expr> {
+        match self {
+            Expr::Num(_) => Vec::new(),
+            Expr::BinOp(_, lhs, rhs) => vec![lhs, rhs],
+            Expr::UnaryOp(_, operand) => vec![operand],
+            Expr::Call(_, args) => args.iter().collect(),
+            Expr::Cond(condition, then, otherwise) => vec![condition, then, otherwise],
+        }
+    }
+
+    // Moves the subtrees out, leaving leaves in their place, so each node is dropped shallow.
+    fn take_children(&mut self, into: &mut Vec<Expr>) {
+        let mut take = |child: &mut Box<Expr>| into.push(std::mem::replace(&mut **child, Expr::Num(0.0)));
+        match self {
+            Expr::Num(_) => {}
+            Expr::BinOp(_, lhs, rhs) => {
+                take(lhs);
+                take(rhs);
+            }
+            Expr::UnaryOp(_, operand) => take(operand),
+            Expr::Call(_, args) => into.append(args),
+            Expr::Cond(condition, then, otherwise) => {
+                take(condition);
+                take(then);
+                take(otherwise);
+            }
+        }
+    }
+}
+
+// Drop, Clone, PartialEq and Debug are written out rather than derived, since the derived forms
+// recurse once per level and a deeply nested tree would overflow the stack.
+impl Drop for Expr {
+    fn drop(&mut self) {
+        let mut pending = Vec::new();
+        self.take_children(&mut pending);
+        while let Some(mut expr) = pending.pop() {
+            expr.take_children(&mut pending);
+        }
+    }
+}
+
+impl Clone for Expr {
+    fn clone(&self) -> Self {
+        // Children are cloned before their parent, which then takes them off `built`.
+        let mut steps = vec![(self, false)];
+        let mut built: Vec<Expr> = Vec::new();
+
+        while let Some((expr, ready)) = steps.pop() {
+            let children = expr.children();
+            if !ready {
+                steps.push((expr, true));
+                steps.extend(children.into_iter().rev().map(|child| (child, false)));
+                continue;
+            }
+            let mut args = built.split_off(built.len() - children.len()).into_iter();
+            let mut next = || Box::new(args.next().expect("every child is cloned before its parent"));
+            built.push(match expr {
+                Expr::Num(n) => Expr::Num(*n),
+                Expr::BinOp(op, ..) => Expr::BinOp(*op, next(), next()),
+                Expr::UnaryOp(op, _) => Expr::UnaryOp(*op, next()),
+                Expr::Call(name, _) => Expr::Call(name.clone(), args.collect()),
+                Expr::Cond(..) => Expr::Cond(next(), next(), next()),
+            });
+        }
+
+        built.pop().expect("the root is cloned last")
+    }
+}
+
+impl PartialEq for Expr {
+    fn eq(&self, other: &Self) -> bool {
+        let mut pairs = vec![(self, other)];
+
+        while let Some((a, b)) = pairs.pop() {
+            let same_node = match (a, b) {
+                (Expr::Num(x), Expr::Num(y)) => x == y,
+                (Expr::BinOp(x, ..), Expr::BinOp(y, ..)) | (Expr::UnaryOp(x, _), Expr::UnaryOp(y, _)) => x == y,
+                (Expr::Call(x, xs), Expr::Call(y, ys)) => x == y && xs.len() == ys.len(),
+                (Expr::Cond(..), Expr::Cond(..)) => true,
+                _ => false,
+            };
+            if !same_node {
+                return false;
+            }
+            pairs.extend(a.children().into_iter().zip(b.children()));
+        }
+
+        true
+    }
+}
+
+// Matches the derived form, such as `BinOp('+', Num(1.0), Num(2.0))`.
+impl fmt::Debug for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = vec![Part::Node(self, false)];
+
+        while let Some(part) = parts.pop() {
+            let expr = match part {
+                Part::Text(text) => {
+                    write!(f, "{}", text)?;
+                    continue;
+                }
+                Part::Node(expr, _) => expr,
+            };
+            let (head, close) = match expr {
+                Expr::Num(n) => (format!("Num({:?}", n), ")"),
+                Expr::BinOp(op, ..) => (format!("BinOp({:?}, ", op), ")"),
+                Expr::UnaryOp(op, _) => (format!("UnaryOp({:?}, ", op), ")"),
+                Expr::Call(name, _) => (format!("Call({:?}, [", name), "])"),
+                Expr::Cond(..) => ("Cond(".to_string(), ")"),
+            };
+            write!(f, "{}", head)?;
+            parts.push(Part::Text(close.to_string()));
+            for (i, child) in expr.children().into_iter().enumerate().rev() {
+                parts.push(Part::Node(child, false));
+                if i > 0 {
+                    parts.push(Part::Text(", ".to_string()));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calculator::{ AngleMode, DivByZeroPolicy };
+
+    fn ast(expr: &str) -> Expr {
+        Calculator::new(expr.to_string()).parse_ast().unwrap()
+    }
+
+    #[test]
+    fn multiplication_nests_under_addition() {
+        let expected = Expr::BinOp(
+            '+',
+            Box::new(Expr::Num(1.0)),
+            Box::new(Expr::BinOp('*', Box::new(Expr::Num(2.0)), Box::new(Expr::Num(3.0)))),
+        );
+        assert_eq!(ast("1 + 2 * 3"), expected);
+        assert_eq!(ast("1 + 2 * 3").eval(), Ok(7.0));
+    }
+
+    #[test]
+    fn deeply_nested_parentheses() {
+        let depth = 10_000;
+        let wrapped = format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        assert_eq!(crate::calculator::evaluate(&wrapped), Ok(1.0));
+        assert_eq!(ast(&wrapped).eval(), Ok(1.0));
+        assert_eq!(ast(&wrapped).to_string(), "1");
+
+        let chained = format!("{}1{}", "(1+".repeat(depth), ")".repeat(depth));
+        assert_eq!(crate::calculator::evaluate(&chained), Ok(10_001.0));
+        let chained = ast(&chained);
+        assert_eq!(chained.eval(), Ok(10_001.0));
+        assert_eq!(chained.to_string(), format!("{}1 + 1{}", "1 + (".repeat(depth - 1), ")".repeat(depth - 1)));
+
+        // Far deeper trees can still be cloned, compared, printed and dropped.
+        let depth = 100_000;
+        let chained = ast(&format!("{}1{}", "(1+".repeat(depth), ")".repeat(depth)));
+        let copy = chained.clone();
+        assert_eq!(copy, chained);
+        assert!(format!("{:?}", copy).starts_with("BinOp('+', Num(1.0), BinOp('+', "));
+        drop(copy);
+        let calls = ast(&format!("{}4{}", "sqrt(".repeat(50_000), ")".repeat(50_000)));
+        assert_ne!(calls, chained);
+    }
+
+    #[test]
+    fn debug_matches_the_derived_form() {
+        assert_eq!(format!("{:?}", ast("3! * 2")), "BinOp('*', UnaryOp('!', Num(3.0)), Num(2.0))");
+        assert_eq!(format!("{:?}", ast("max(1, 2)")), "Call(\"max\", [Num(1.0), Num(2.0)])");
+        assert_eq!(format!("{:?}", ast("1 ? 2 : 3")), "Cond(Num(1.0), Num(2.0), Num(3.0))");
+    }
+
+    #[test]
+    fn display_keeps_only_needed_parentheses() {
+        assert_eq!(ast("2 + (3 * 4)").to_string(), "2 + 3 * 4");
+        assert_eq!(ast("(2 + 3) * 4").to_string(), "(2 + 3) * 4");
+        assert_eq!(ast("2 - (3 - 4)").to_string(), "2 - (3 - 4)");
+        assert_eq!(ast("(2 - 3) - 4").to_string(), "2 - 3 - 4");
+        assert_eq!(ast("(2 ^ 3) ^ 4").to_string(), "(2 ^ 3) ^ 4");
+        assert_eq!(ast("-2 ^ 2").to_string(), "-2 ^ 2");
+        assert_eq!(ast("(-2) ^ 2").to_string(), "(-2) ^ 2");
+        assert_eq!(ast("-(2 + 3) * -sqrt(4)").to_string(), "-(2 + 3) * -sqrt(4)");
+        assert_eq!(ast("(-3)!").to_string(), "(-3)!");
+    }
+
+    #[test]
+    fn eval_in_follows_the_calculator_configuration() {
+        let eval_in = |expr: &str, calculator: Calculator| ast(expr).eval_in(&calculator);
+        assert_eq!(eval_in("sin(90)", Calculator::new(String::new()).with_angle_mode(AngleMode::Degrees)), Ok(1.0));
+        let infinity = Calculator::new(String::new()).with_div_by_zero(DivByZeroPolicy::Infinity);
+        assert_eq!(eval_in("1 / 0", infinity), Ok(f64::INFINITY));
+        let epsilon = Calculator::new(String::new()).with_zero_epsilon(1e-9);
+        assert!(matches!(eval_in("1 / 1e-12", epsilon), Err(CalculatorError::ZeroDivision { .. })));
+        let integer = Calculator::new(String::new()).with_integer_mode();
+        assert_eq!(eval_in("7 / 2", integer), Err(CalculatorError::InvalidExpression));
+        assert_eq!(eval_in("0.1 + 0.2", Calculator::new(String::new()).with_precision(2)), Ok(0.3));
+        let typed = Calculator::<f32>::new_typed(String::new());
+        assert_eq!(ast("0.1 + 0.2").eval_in(&typed), Ok(0.1f32 + 0.2f32));
+    }
+
+    #[test]
+    fn registered_operators_evaluate_and_print() {
+        let mut calculator = Calculator::new("2 @ 3 * 4".to_string());
+        calculator.register_operator('@', 11, f64::hypot).unwrap();
+        let tree = calculator.parse_ast().unwrap();
+        assert_eq!(tree.eval_in(&calculator), Ok(3.605551275463989 * 4.0));
+        assert_eq!(tree.to_string(), "(2 @ 3) * 4");
+
+        calculator.set_expr("2 * 3 @ 4 + 1".to_string());
+        let tree = calculator.parse_ast().unwrap();
+        assert_eq!(tree.eval_in(&calculator), Ok(11.0));
+        assert_eq!(tree.to_string(), "2 * (3 @ 4) + 1");
+
+        calculator.set_expr("(1 @ 2) @ 3".to_string());
+        assert_eq!(calculator.parse_ast().unwrap().to_string(), "(1 @ 2) @ 3");
+    }
+
+    #[test]
+    fn chained_comparisons_expand_unless_bracketed() {
+        assert_eq!(ast("1 < 2 < 3").eval(), Ok(1.0));
+        assert_eq!(ast("1 < 5 < 3").eval(), Ok(0.0));
+        assert_eq!(ast("1 < 2 < 3 < 4").eval(), Ok(1.0));
+        assert_eq!(ast("(1 < 5) < 3").eval(), Ok(1.0));
+        assert_eq!(ast("(1 < 5) < 3").to_string(), "(1 < 5) < 3");
+        assert_eq!(ast("3 > 2 > 1").eval(), Ok(1.0));
+        assert_eq!(crate::calculator::evaluate("3 > 2 > 1"), Ok(0.0));
+    }
+}
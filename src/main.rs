@@ -1,17 +1,487 @@
-use std::{ env, process };
+use std::{ env, fs, io::{ self, Read, Write }, process, time::Duration };
 
-use eval::calculator::Calculator;
+use eval::calculator::{ supported_functions, supported_operators, CalcValue, Calculator, CalculatorError };
+
+fn repl() {
+    let stdin = io::stdin();
+    let mut line = String::new();
+    let mut calculator = Calculator::new(String::new());
+
+    loop {
+        print!("> ");
+        io::stdout().flush().ok();
+
+        line.clear();
+        match stdin.read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+
+        let expr = line.trim();
+        if expr.is_empty() {
+            continue;
+        }
+        if expr == "quit" {
+            break;
+        }
+        if expr == "help" || expr == "help functions" {
+            println!("Functions: {}", supported_functions().join(", "));
+            println!("Operators: {}", supported_operators().join(" "));
+            continue;
+        }
+        if expr == "M+" {
+            calculator.memory_add();
+            println!("M = {}", calculator.memory());
+            continue;
+        }
+        if expr == "MC" {
+            calculator.memory_clear();
+            continue;
+        }
+
+        calculator.set_expr(expr.to_string());
+        match calculator.eval_typed() {
+            Ok(n) => println!("{}", n),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
+
+fn usage() -> (String, i32) {
+    (
+        "Usage: cargo run [--hex | --oct | --bin] [--precision N] [--trace] [--explain] [--json] [--sci] [--currency] [--quiet] [--repeat N] \"expression\" | --file PATH"
+            .to_string(),
+        64,
+    )
+}
+
+fn failure(e: CalculatorError) -> (String, i32) {
+    (e.to_string(), e.exit_code())
+}
+
+// Whether `expr` holds several `;`-separated statements. A `;` in a comment does not count.
+fn has_statements(expr: &str) -> bool {
+    expr.lines().any(|line| line.split('#').next().unwrap_or_default().contains(';'))
+}
+
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    args.iter().position(|arg| arg == flag).map(|i| args.remove(i)).is_some()
+}
+
+fn take_value(args: &mut Vec<String>, flag: &str) -> Result<Option<String>, (String, i32)> {
+    match args.iter().position(|arg| arg == flag) {
+        Some(i) if i + 1 < args.len() => {
+            let value = args.remove(i + 1);
+            args.remove(i);
+            Ok(Some(value))
+        }
+        Some(_) => Err(usage()),
+        None => Ok(None),
+    }
+}
+
+fn format_radix(value: i64, flag: &str) -> String {
+    let sign = if value < 0 { "-" } else { "" };
+    let magnitude = value.unsigned_abs();
+    match flag {
+        "--hex" => format!("{}{:#x}", sign, magnitude),
+        "--oct" => format!("{}{:#o}", sign, magnitude),
+        "--bin" => format!("{}{:#b}", sign, magnitude),
+        _ => value.to_string(),
+    }
+}
+
+// Results at or above 1e9, or below 1e-4 but not zero, are shown as `{:e}`, so `0.0000001` is
+// `1e-7`. Everything in between keeps the default formatting.
+fn format_sci(value: f64) -> String {
+    let magnitude = value.abs();
+    if magnitude >= 1e9 || (magnitude != 0.0 && magnitude < 1e-4) {
+        format!("{:e}", value)
+    } else {
+        CalcValue::from(value).to_string()
+    }
+}
+
+// Two decimals with `,` between groups of thousands, so `1234567.5` is `1,234,567.50`.
+fn format_currency(value: f64) -> String {
+    let digits = format!("{:.2}", value.abs());
+    let (whole, cents) = digits.split_at(digits.len() - 3);
+
+    let mut grouped = String::new();
+    for (i, c) in whole.chars().enumerate() {
+        if i > 0 && (whole.len() - i) % 3 == 0 {
+            grouped.push(',');
+        }
+        grouped.push(c);
+    }
+
+    let sign = if value < 0.0 && digits != "0.00" { "-" } else { "" };
+    format!("{}{}{}", sign, grouped, cents)
+}
+
+fn json_string(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_result(result: Result<f64, CalculatorError>) -> String {
+    match result {
+        Ok(value) if value.is_finite() => format!("{{\"result\": {:?}, \"error\": null}}", value),
+        Ok(value) if value.is_nan() => json_result(Err(CalculatorError::MathDomain)),
+        Ok(_) => json_result(Err(CalculatorError::Overflow)),
+        Err(e) => format!("{{\"result\": null, \"error\": {}}}", json_string(&e.to_string())),
+    }
+}
+
+// What a successful run prints, with warnings going to stderr so the result alone can be
+// captured. The exit code is nonzero when some of the output reports an error, as when one
+// line of a `--file` fails while the others succeed.
+#[derive(Debug, PartialEq)]
+struct Output {
+    stdout: String,
+    warnings: Vec<String>,
+    exit_code: i32,
+}
+
+impl From<String> for Output {
+    fn from(stdout: String) -> Self {
+        Output { stdout, warnings: Vec::new(), exit_code: 0 }
+    }
+}
+
+// Collects numbered results, one per line. Failed entries are reported in place, and the exit
+// code is that of the first of them.
+fn report<I>(label: &str, results: I) -> Output
+where
+    I: IntoIterator<Item = (usize, Result<CalcValue, CalculatorError>)>,
+{
+    let mut lines = Vec::new();
+    let mut exit_code = 0;
+    for (n, result) in results {
+        match result {
+            Ok(value) => lines.push(format!("{} {}: {}", label, n, value)),
+            Err(e) => {
+                lines.push(format!("{} {}: {}", label, n, e));
+                if exit_code == 0 {
+                    exit_code = e.exit_code();
+                }
+            }
+        }
+    }
+
+    Output { stdout: lines.join("\n"), warnings: Vec::new(), exit_code }
+}
+
+fn run(args: &[String]) -> Result<Output, (String, i32)> {
+    let mut args = args.to_vec();
+
+    let is_radix = |arg: &String| matches!(arg.as_str(), "--hex" | "--oct" | "--bin");
+    if args.iter().filter(|arg| is_radix(arg)).count() > 1 {
+        return Err(usage());
+    }
+    let radix = args.iter().position(is_radix).map(|i| args.remove(i));
+    let trace = take_flag(&mut args, "--trace");
+    let explain = take_flag(&mut args, "--explain");
+    let json = take_flag(&mut args, "--json");
+    let sci = take_flag(&mut args, "--sci");
+    let currency = take_flag(&mut args, "--currency");
+    let quiet = take_flag(&mut args, "--quiet");
+    let precision = match take_value(&mut args, "--precision")? {
+        Some(digits) => Some(digits.parse::<usize>().map_err(|_| usage())?),
+        None => None,
+    };
+    let repeat = match take_value(&mut args, "--repeat")? {
+        Some(count) => Some(count.parse::<usize>().ok().filter(|&count| count > 0).ok_or_else(usage)?),
+        None => None,
+    };
+    let file = take_value(&mut args, "--file")?;
+
+    let mut calculator = Calculator::new(String::new());
+    if let Some(digits) = precision {
+        calculator = calculator.with_precision(digits);
+    }
+
+    // Each line of a file is printed as a plain numbered result, so the flags that change how
+    // a single result is shown are refused rather than ignored.
+    if let Some(path) = file {
+        let shapes_output = radix.is_some() || trace || explain || json || sci || currency || quiet;
+        if !args.is_empty() || repeat.is_some() || shapes_output {
+            return Err(usage());
+        }
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| (format!("Error: cannot read '{}': {}.", path, e), 66))?;
+
+        let results = contents
+            .lines()
+            .enumerate()
+            .map(|(n, line)| (n + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .map(|(n, line)| {
+                calculator.set_expr(line.to_string());
+                (n, calculator.eval_typed())
+            })
+            .collect::<Vec<_>>();
+        return Ok(report("Line", results));
+    }
+
+    if args.is_empty() {
+        return Err(usage());
+    }
+
+    let expr = if args.len() == 1 && args[0] == "-" {
+        let mut input = String::new();
+        if io::stdin().read_to_string(&mut input).is_err() || input.trim().is_empty() {
+            return Err(failure(CalculatorError::InvalidExpression));
+        }
+        input.trim().to_string()
+    } else {
+        args.join(" ")
+    };
+    calculator.set_expr(expr.clone());
+
+    // Only a single plainly printed result is timed, so `--repeat` is refused rather than
+    // ignored alongside the other output modes.
+    if repeat.is_some() && (json || trace || explain || has_statements(&expr)) {
+        return Err(usage());
+    }
+
+    if json {
+        return Ok(json_result(calculator.eval()).into());
+    }
+
+    let mut lines = Vec::new();
+
+    if explain {
+        let notes = calculator.explain().map_err(failure)?;
+        if notes.is_empty() {
+            lines.push("There are no precedence decisions to make.".to_string());
+        }
+        lines.extend(notes);
+    }
+
+    if trace || explain {
+        let (value, steps) = calculator.eval_verbose().map_err(failure)?;
+        lines.extend(steps);
+        lines.push(if quiet { value.to_string() } else { format!("Result: {}", value) });
+        return Ok(lines.join("\n").into());
+    }
+
+    if has_statements(&expr) {
+        let results = calculator.eval_all().into_iter().enumerate();
+        return Ok(report("Result", results.map(|(n, result)| (n + 1, result.map(CalcValue::from)))));
+    }
+
+    // `--repeat N` evaluates the expression N times on a separate calculator and reports the
+    // time they took together, so `ans` in the printed result is not affected by the runs.
+    let elapsed = match repeat {
+        Some(count) => {
+            let mut bench = Calculator::new(expr.clone());
+            let mut total = Duration::ZERO;
+            for _ in 0..count {
+                let (result, elapsed) = bench.eval_timed();
+                result.map_err(failure)?;
+                total += elapsed;
+            }
+            Some(format!("Elapsed: {:?} for {} evaluations", total, count))
+        }
+        None => None,
+    };
+
+    let mut warnings = Vec::new();
+    let value = if currency && radix.is_none() {
+        format_currency(calculator.eval().map_err(failure)?)
+    } else if sci && radix.is_none() {
+        format_sci(calculator.eval().map_err(failure)?)
+    } else {
+        match (calculator.eval_typed().map_err(failure)?, radix) {
+            (CalcValue::Int(n), Some(flag)) => format_radix(n, &flag),
+            (n, Some(_)) => {
+                if !quiet {
+                    warnings.push(format!("{} is not a whole number, showing it in decimal.", n));
+                }
+                n.to_string()
+            }
+            (n, None) => n.to_string(),
+        }
+    };
+
+    // `--quiet` prints the bare value so scripts can capture it.
+    let output = if quiet { value } else { format!("Result: {}", value) };
+    let stdout = match elapsed {
+        Some(elapsed) if !quiet => format!("{}\n{}", output, elapsed),
+        _ => output,
+    };
+    Ok(Output { stdout, warnings, exit_code: 0 })
+}
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    if args.is_empty() {
+        repl();
+        return;
+    }
+
+    match run(&args) {
+        Ok(output) => {
+            for warning in &output.warnings {
+                eprintln!("Warning: {}", warning);
+            }
+            println!("{}", output.stdout);
+            if output.exit_code != 0 {
+                process::exit(output.exit_code);
+            }
+        }
+        Err((message, code)) => {
+            eprintln!("\n{}\n", message);
+            process::exit(code);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn printed(stdout: &str) -> Output {
+        Output::from(stdout.to_string())
+    }
+
+    #[test]
+    fn format_radix_uses_the_requested_base() {
+        assert_eq!(format_radix(255, "--hex"), "0xff");
+        assert_eq!(format_radix(10, "--bin"), "0b1010");
+        assert_eq!(format_radix(8, "--oct"), "0o10");
+        assert_eq!(format_radix(-255, "--hex"), "-0xff");
+    }
+
+    #[test]
+    fn radix_flags_fall_back_to_decimal_for_fractions() {
+        assert_eq!(run(&args(&["--hex", "255"])), Ok(printed("Result: 0xff")));
+        let warnings = vec!["2.5 is not a whole number, showing it in decimal.".to_string()];
+        assert_eq!(
+            run(&args(&["--hex", "2.5"])),
+            Ok(Output { stdout: "Result: 2.5".to_string(), warnings, exit_code: 0 })
+        );
+        assert_eq!(run(&args(&["--hex", "--bin", "3"])), Err(usage()));
+        assert_eq!(run(&args(&["--oct", "3", "--oct"])), Err(usage()));
+    }
+
+    #[test]
+    fn precision_flag_rounds_the_result() {
+        assert_eq!(run(&args(&["--precision", "2", "0.1 + 0.2"])), Ok(printed("Result: 0.3")));
+        assert_eq!(run(&args(&["--precision", "x", "1"])), Err(usage()));
+    }
+
+    #[test]
+    fn missing_expressions_print_usage() {
+        assert_eq!(run(&[]).unwrap_err().1, 64);
+        assert_eq!(run(&args(&["1 / 0"])), Err(("Error: cannot divide 1 by 0.".to_string(), 3)));
+    }
+
+    #[test]
+    fn unquoted_arguments_are_joined() {
+        assert_eq!(run(&args(&["2", "+", "2"])), Ok(printed("Result: 4")));
+        assert_eq!(run(&args(&["2 + 2"])), Ok(printed("Result: 4")));
+    }
+
+    #[test]
+    fn json_results_and_errors() {
+        assert_eq!(json_result(Ok(42.0)), "{\"result\": 42.0, \"error\": null}");
+        assert_eq!(
+            json_result(Err(CalculatorError::ZeroDivision { dividend: 1.0 })),
+            "{\"result\": null, \"error\": \"Error: cannot divide 1 by 0.\"}"
+        );
+        assert_eq!(json_string("say \"hi\"\n"), "\"say \\\"hi\\\"\\n\"");
+    }
+
+    #[test]
+    fn file_flag_reports_each_line() {
+        let path = std::env::temp_dir().join(format!("calculator-test-{}.txt", std::process::id()));
+        fs::write(&path, "1 + 1\n# a comment\n\n1 / 0\n2 * 3\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let stdout = "Line 1: 2\nLine 4: Error: cannot divide 1 by 0.\nLine 5: 6".to_string();
+        assert_eq!(run(&args(&["--file", path])), Ok(Output { stdout, warnings: Vec::new(), exit_code: 3 }));
+        assert_eq!(run(&args(&["--file", path, "1"])), Err(usage()));
+        for flag in ["--json", "--hex", "--sci", "--currency", "--quiet", "--trace", "--explain"] {
+            assert_eq!(run(&args(&["--file", path, flag])), Err(usage()));
+        }
+        fs::remove_file(path).unwrap();
+        assert_eq!(run(&args(&["--file", path])).unwrap_err().1, 66);
+    }
+
+    #[test]
+    fn run_handles_flags_in_any_position() {
+        assert_eq!(run(&args(&["7 // 2"])), Ok(printed("Result: 3")));
+        assert_eq!(run(&args(&["1 + 1", "--bin"])), Ok(printed("Result: 0b10")));
+        assert_eq!(run(&args(&["1; 2 * 3"])), Ok(printed("Result 1: 1\nResult 2: 6")));
+        assert_eq!(run(&args(&["2 * 3 # a;b"])), Ok(printed("Result: 6")));
+        let stdout = "Result 1: Error: cannot divide 1 by 0.\nResult 2: 2".to_string();
+        assert_eq!(run(&args(&["1 / 0; 2"])), Ok(Output { stdout, warnings: Vec::new(), exit_code: 3 }));
+        assert_eq!(
+            run(&args(&["2 + @"])),
+            Err(("Error at position 4: The expression contains an unsupported token '@'.".to_string(), 2))
+        );
+        assert_eq!(run(&args(&["--precision"])), Err(usage()));
+    }
+
+    #[test]
+    fn format_sci_switches_at_the_thresholds() {
+        assert_eq!(format_sci(1_000_000_000.0), "1e9");
+        assert_eq!(format_sci(0.0000001), "1e-7");
+        assert_eq!(format_sci(-2.5e12), "-2.5e12");
+        assert_eq!(format_sci(123.5), "123.5");
+        assert_eq!(format_sci(0.0), "0");
+        assert_eq!(run(&args(&["--sci", "1 / 10000000"])), Ok(printed("Result: 1e-7")));
+    }
+
+    #[test]
+    fn format_currency_groups_thousands() {
+        assert_eq!(format_currency(1234567.5), "1,234,567.50");
+        assert_eq!(format_currency(-1234.5), "-1,234.50");
+        assert_eq!(format_currency(0.5), "0.50");
+        assert_eq!(format_currency(-0.001), "0.00");
+        assert_eq!(run(&args(&["--currency", "1000 / 3"])), Ok(printed("Result: 333.33")));
+    }
+
+    #[test]
+    fn quiet_flag_prints_the_bare_value() {
+        assert_eq!(run(&args(&["--quiet", "2 + 2"])), Ok(printed("4")));
+        assert_eq!(run(&args(&["--quiet", "--hex", "2.5"])), Ok(printed("2.5")));
+        assert_eq!(run(&args(&["--quiet", "1 / 0"])), Err(("Error: cannot divide 1 by 0.".to_string(), 3)));
+    }
 
-    if args.len() != 2 {
-        eprintln!("\nUsage: cargo run \"expression\"\n");
-        process::exit(1);
+    #[test]
+    fn repeat_flag_prints_one_result() {
+        let output = run(&args(&["--repeat", "3", "2 + 2"])).unwrap();
+        let lines: Vec<&str> = output.stdout.lines().collect();
+        assert_eq!(lines[0], "Result: 4");
+        assert!(lines[1].starts_with("Elapsed: ") && lines[1].ends_with(" for 3 evaluations"));
+        assert_eq!(run(&args(&["--repeat", "3", "--quiet", "2 + 2"])), Ok(printed("4")));
+        assert_eq!(run(&args(&["--repeat", "0", "2 + 2"])), Err(usage()));
     }
 
-    match Calculator::new(args[1].to_string()).eval() {
-        Ok(n) => println!("Result: {}", n),
-        Err(e) => eprintln!("\n{}\n", e),
+    #[test]
+    fn repeat_flag_refuses_other_output_modes() {
+        for flag in ["--json", "--trace", "--explain"] {
+            assert_eq!(run(&args(&["--repeat", "3", flag, "2 + 2"])), Err(usage()));
+        }
+        assert_eq!(run(&args(&["--repeat", "3", "1; 2"])), Err(usage()));
+        assert_eq!(run(&args(&["--repeat", "3", "--file", "exprs.txt"])), Err(usage()));
     }
 }